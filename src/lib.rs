@@ -0,0 +1,12 @@
+//! Parser combinators, and the netpbm image parsers built out of them.
+
+// Both features below need a nightly toolchain; the crate itself builds on stable.
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+#![cfg_attr(feature = "trace-macros", feature(trace_macros))]
+
+// Prints every macro expansion in the modules below, `parse_do!` ones included.
+#[cfg(feature = "trace-macros")]
+trace_macros!(true);
+
+pub mod parser;
+pub mod pgm;
@@ -1,192 +1,663 @@
-#![feature(result_flattening)]
-#![feature(trace_macros)]
-
-use bstr::ByteSlice;
-use newtype::NewType;
-use std::cmp::Ordering;
-use std::error::Error;
+use rwh_ch10_rust::parser::*;
+use rwh_ch10_rust::pgm::*;
+use std::convert::TryFrom;
 use std::fmt;
-use std::io::Read;
+use std::io::{self, BufRead, Read, Seek, Write};
+
+/********/
+/* Main */
+/********/
 
-/********************************************/
-/* Parser general definition and properties */
-/********************************************/
+const USAGE: &str = "\
+usage: rwh-ch10-rust [--json] <command> [<args>]
+
+commands:
+    info [<file>...]                                    print the header of each image
+    convert [--plain] [--line-width <n>] [<in> [<out>]]  rewrite an image, raw unless --plain
+    validate [--lenient] [<file>...]                    check each image, padding and clamping
+                                                        what is off with --lenient
+    view [--columns <n>] [<file>]                       draw an image with characters
+    stats [<file>]                                      print statistics of the samples
+
+Images are PGM ones (P2 or P5); the rest of the netpbm family is reported as
+unsupported. Files default to standard input and output, which `-` also stands for. With --json, each
+result or error is printed as a JSON object on a line of its own.
+
+exit codes:
+    0  success
+    2  bad command line
+    3  I/O error
+    4  malformed image
+    5  truncated image
+    6  image breaking the format (dimensions, maxval, samples)
+    7  image exceeding the parsing limits
+    8  image of the netpbm family other than PGM, which is all this tool handles";
+
+/// A command line, as given to `main`.
+#[derive(Debug, PartialEq)]
+struct Cli {
+    json: bool,
+    command: Command,
+}
 
-trait Parser<'a, Out> {
-    fn parse(&self, input: ParseInput<'a>) -> ParseResult<'a, Out>;
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    Info(Vec<String>),
+    Convert {
+        input: String,
+        output: String,
+        encoding: Encoding,
+        line_width: Option<usize>,
+    },
+    Validate {
+        paths: Vec<String>,
+        lenient: bool,
+    },
+    View {
+        path: String,
+        columns: usize,
+    },
+    Stats(String),
 }
 
-impl<'a, Out, F> Parser<'a, Out> for F
-where
-    F: Fn(ParseInput<'a>) -> ParseResult<Out>,
-{
-    fn parse(&self, input: ParseInput<'a>) -> ParseResult<'a, Out> {
-        self(input)
+/// Options and paths following a command.
+struct Args {
+    options: Vec<(String, Option<String>)>,
+    paths: Vec<String>,
+}
+
+impl Args {
+    /// Sorts out `args`, given which options are `flags` and which take a value.
+    fn split(
+        mut args: impl Iterator<Item = String>,
+        flags: &[&str],
+        valued: &[&str],
+    ) -> Result<Args, String> {
+        let (mut options, mut paths) = (vec![], vec![]);
+
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if flags.contains(&name) => options.push((name.to_string(), None)),
+                Some(name) if valued.contains(&name) => {
+                    let value = args.next().ok_or(format!("--{} needs a value", name))?;
+                    options.push((name.to_string(), Some(value)));
+                }
+                Some(name) => return Err(format!("unknown option --{}", name)),
+                None => paths.push(arg),
+            }
+        }
+
+        Ok(Args { options, paths })
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| option == name)
     }
+
+    fn number(&self, name: &str) -> Result<Option<usize>, String> {
+        let value = self.options.iter().rev().find(|(option, _)| option == name);
+
+        value
+            .and_then(|(_, value)| value.as_ref())
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("--{} takes a number, not {:?}", name, value))
+            })
+            .transpose()
+    }
+
+    /// The only path given, standard input if none.
+    fn single_path(self) -> Result<String, String> {
+        match <[String; 1]>::try_from(self.paths) {
+            Ok([path]) => Ok(path),
+            Err(paths) if paths.is_empty() => Ok("-".to_string()),
+            Err(_) => Err("too many files".to_string()),
+        }
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Cli, String> {
+    let mut args = args.into_iter().peekable();
+    let json = args.next_if_eq(&"--json").is_some();
+    let command = args.next().ok_or("missing command")?;
+
+    let command = match command.as_str() {
+        "help" | "-h" | "--help" => Command::Help,
+        "info" => {
+            let paths = Args::split(args, &[], &[])?.paths;
+            Command::Info(if paths.is_empty() {
+                vec!["-".to_string()]
+            } else {
+                paths
+            })
+        }
+        "convert" => {
+            let args = Args::split(args, &["plain"], &["line-width"])?;
+            let encoding = if args.flag("plain") {
+                Encoding::Plain
+            } else {
+                Encoding::Raw
+            };
+            let line_width = args
+                .number("line-width")?
+                .or(WriteOptions::default().line_width);
+
+            let mut paths = args.paths.into_iter();
+            let input = paths.next().unwrap_or_else(|| "-".to_string());
+            let output = paths.next().unwrap_or_else(|| "-".to_string());
+            if paths.next().is_some() {
+                return Err("too many files".to_string());
+            }
+
+            Command::Convert {
+                input,
+                output,
+                encoding,
+                line_width,
+            }
+        }
+        "validate" => {
+            let args = Args::split(args, &["lenient"], &[])?;
+            let lenient = args.flag("lenient");
+            let paths = if args.paths.is_empty() {
+                vec!["-".to_string()]
+            } else {
+                args.paths
+            };
+
+            Command::Validate { paths, lenient }
+        }
+        "view" => {
+            let args = Args::split(args, &[], &["columns"])?;
+            let columns = args.number("columns")?.unwrap_or(80);
+
+            Command::View {
+                path: args.single_path()?,
+                columns,
+            }
+        }
+        "stats" => Command::Stats(Args::split(args, &[], &[])?.single_path()?),
+        _ => return Err(format!("unknown command {:?}", command)),
+    };
+
+    Ok(Cli { json, command })
 }
 
-fn map<'a, Out, Out1, F, P>(parser: P, fun: F) -> impl Parser<'a, Out1>
-where
-    F: Fn(Out) -> Out1,
-    P: Parser<'a, Out>,
-{
-    move |input| parser.parse(input).map(|(out, rest)| (fun(out), rest))
+/// Why a command failed on one of its files.
+#[derive(Debug)]
+enum Failure {
+    Io(io::Error),
+    /// A parse error, along with the input it was located in when there is one to show.
+    Parse(ParseError, Option<Vec<u8>>),
+    /// A netpbm image, but no PGM.
+    Unsupported(ImageKind),
 }
 
-fn and_then<'a, Out, Out1, F, P, P1>(parser: P, fun: F) -> impl Parser<'a, Out1>
-where
-    F: Fn(Out) -> P1,
-    P: Parser<'a, Out>,
-    P1: Parser<'a, Out1>,
-{
-    move |input| {
-        parser
-            .parse(input)
-            .and_then(|(out, rest)| fun(out).parse(rest))
+impl From<io::Error> for Failure {
+    fn from(er: io::Error) -> Self {
+        Failure::Io(er)
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum ParseErr {
-    NoValidFieldLeft,
-    NoHeaderMatch,
-    Utf8Error(bstr::Utf8Error),
-    InvalidNum(std::num::ParseIntError),
-    InvByte(String), // Ugly hack to permit derivation of `PartialEq`
+impl From<DecodeErr> for Failure {
+    fn from(er: DecodeErr) -> Self {
+        match er {
+            DecodeErr::Io(er) => Failure::Io(er),
+            DecodeErr::Parse(er) => Failure::Parse(er, None),
+        }
+    }
 }
 
-macro_rules! parse_do {
-    (return $val:expr,) => {
-        move |input| Ok(($val, input))
-    };
-    ($out:tt <- $parser:expr, $($tail:tt)*) => {
-        and_then($parser, move |$out| parse_do!($($tail)*))
-    };
-    ($parser:tt, $($tail:tt)*) => {
-        and_then($parser, move |_| parse_do!($($tail)*))
-    };
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Io(_) => 3,
+            Failure::Parse(er, _) => classify(&er.err).1,
+            Failure::Unsupported(_) => 8,
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        match self {
+            Failure::Io(er) => Json::Obj(vec![
+                ("kind", "io".into()),
+                ("message", er.to_string().into()),
+            ]),
+            Failure::Parse(er, _) => parse_error_json(er),
+            Failure::Unsupported(kind) => Json::Obj(vec![
+                ("kind", "unsupported".into()),
+                ("message", unsupported_message(*kind).into()),
+            ]),
+        }
+    }
 }
 
-/****************/
-/* PGM Datatype */
-/****************/
+/// Fails on images of the netpbm family this tool doesn't handle, given the start of one.
+fn check_kind(start: &[u8]) -> Result<(), Failure> {
+    match probe_kind(start) {
+        Some(kind) if kind != ImageKind::Pgm => Err(Failure::Unsupported(kind)),
+        _ => Ok(()),
+    }
+}
 
-#[derive(Debug)]
-struct PGM {
-    width: usize,
-    height: usize,
-    max_grey_val: u8,
-    contents: Contents,
+fn unsupported_message(kind: ImageKind) -> String {
+    format!("{} images are not supported, only PGM ones", kind.name())
+}
+
+/// What kind of problem `err` is, as named in JSON output and told by the exit code.
+fn classify(err: &ParseErr) -> (&'static str, i32) {
+    match err {
+        ParseErr::NoValidFieldLeft
+        | ParseErr::Expected(_)
+        | ParseErr::Utf8Error(_)
+        | ParseErr::InvalidNum(_)
+        | ParseErr::InvalidBit(_)
+        | ParseErr::UnknownPamKeyword(_)
+        | ParseErr::UnknownTupleType(_)
+        | ParseErr::MissingPamField(_)
+        | ParseErr::TupleDepthMismatch(..) => ("malformed", 4),
+        ParseErr::UnexpectedEof { .. } => ("truncated", 5),
+        ParseErr::InvalidDimensions { .. }
+        | ParseErr::InvalidDepth(_)
+        | ParseErr::InvalidMaxval(_)
+        | ParseErr::SampleExceedsMaxval { .. } => ("invalid", 6),
+        ParseErr::LimitExceeded { .. } => ("too-large", 7),
+        ParseErr::Context(_, er) => classify(er),
+    }
+}
+
+fn parse_error_json(er: &ParseError) -> Json {
+    Json::Obj(vec![
+        ("kind", classify(&er.err).0.into()),
+        ("message", er.err.to_string().into()),
+        ("offset", er.position.offset.into()),
+        ("line", er.position.line.into()),
+        ("column", er.position.column.into()),
+    ])
+}
+
+/// Just enough JSON for the output of the command-line tool.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(&'static str, Json)>),
 }
 
-#[derive(NewType)]
-struct Contents(Vec<u8>);
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Num(n as f64)
+    }
+}
+
+impl From<u16> for Json {
+    fn from(n: u16) -> Self {
+        Json::Num(n.into())
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::Str(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::Str(s)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
 
-impl fmt::Debug for Contents {
+impl fmt::Display for Json {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}...<contents>", &self.0[0..20].as_bstr())
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Num(n) => write!(f, "{}", n),
+            Json::Str(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Json::Arr(elems) => {
+                write!(f, "[")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { "," }, elem)?;
+                }
+                write!(f, "]")
+            }
+            Json::Obj(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    write!(f, "{}{}:{}", sep, Json::from(*key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
     }
 }
 
-type ParseInput<'a> = &'a [u8];
-type ParseResult<'a, T> = Result<(T, ParseInput<'a>), (ParseErr, ParseInput<'a>)>;
+/// Where `run` prints results to, and, unless asked for JSON, problems.
+struct Output<'a> {
+    json: bool,
+    out: &'a mut dyn Write,
+    errs: &'a mut dyn Write,
+    /// Exit code of the first failure, if any.
+    code: i32,
+}
 
-/*******************/
-/* Parser builders */
-/*******************/
+impl Output<'_> {
+    fn fail(&mut self, path: &str, failure: Failure) -> io::Result<()> {
+        if self.code == 0 {
+            self.code = failure.exit_code();
+        }
+
+        if self.json {
+            let record = Json::Obj(vec![("file", path.into()), ("error", failure.to_json())]);
+            return writeln!(self.out, "{}", record);
+        }
+
+        match failure {
+            Failure::Io(er) => writeln!(self.errs, "{}: {}", path, er),
+            Failure::Parse(er, Some(input)) => {
+                writeln!(self.errs, "{}: {}", path, er.report(&input))
+            }
+            Failure::Parse(er, None) => writeln!(self.errs, "{}: {}", path, er),
+            Failure::Unsupported(kind) => {
+                writeln!(self.errs, "{}: {}", path, unsupported_message(kind))
+            }
+        }
+    }
+}
 
-fn parse_pgm(input: ParseInput) -> ParseResult<PGM> {
-    let parser = parse_do! {
-        match_header_version,
-        width <- get_num,
-        height <- get_num,
-        max_grey_val <- get_num,
-        contents <- move |i| get_bytes(i, (width * height) as usize),
+/// Runs `cli`, printing results to `out` and, unless asked for JSON, problems to `errs`.
+/// Returns the exit code.
+fn run(cli: &Cli, out: &mut dyn Write, errs: &mut dyn Write) -> io::Result<i32> {
+    let json = cli.json;
+    let mut output = Output {
+        json,
+        out,
+        errs,
+        code: 0,
+    };
 
-        return PGM {
-            width: width as usize,
-            height: height as usize,
-            max_grey_val: max_grey_val as u8,
-            contents: contents.into(),
+    match &cli.command {
+        Command::Help => writeln!(output.out, "{}", USAGE)?,
+        Command::Info(paths) => {
+            for path in paths {
+                match read_header(path) {
+                    Ok(header) => print_header(output.out, json, path, &header)?,
+                    Err(failure) => output.fail(path, failure)?,
+                }
+            }
+        }
+        Command::Convert {
+            input,
+            output: path,
+            encoding,
+            line_width,
+        } => {
+            let options = WriteOptions {
+                encoding: *encoding,
+                line_width: *line_width,
+                ..WriteOptions::default()
+            };
+
+            match load_pgm(input, &ParseOptions::default()) {
+                Ok((pgm, _)) if path == "-" => write_pgm(&mut output.out, &pgm, &options)?,
+                Ok((pgm, _)) => {
+                    let written = std::fs::File::create(path).and_then(|file| {
+                        let mut file = io::BufWriter::new(file);
+                        write_pgm(&mut file, &pgm, &options)?;
+                        file.flush()
+                    });
+                    if let Err(er) = written {
+                        output.fail(path, Failure::Io(er))?;
+                    }
+                }
+                Err(failure) => output.fail(input, failure)?,
+            }
+        }
+        Command::Validate { paths, lenient } => {
+            let options = ParseOptions {
+                pad_truncated: *lenient,
+                strictness: if *lenient {
+                    Strictness::Lenient
+                } else {
+                    Strictness::Strict
+                },
+                ..ParseOptions::default()
+            };
+
+            for path in paths {
+                match load_pgm(path, &options) {
+                    Ok((_, warnings)) if json => {
+                        let warnings = warnings.iter().map(parse_error_json).collect();
+                        let record = Json::Obj(vec![
+                            ("file", path.as_str().into()),
+                            ("warnings", Json::Arr(warnings)),
+                        ]);
+                        writeln!(output.out, "{}", record)?;
+                    }
+                    Ok((_, warnings)) => {
+                        for warning in warnings {
+                            writeln!(output.errs, "{}: warning: {}", path, warning)?;
+                        }
+                        writeln!(output.out, "{}: ok", path)?;
+                    }
+                    Err(failure) => output.fail(path, failure)?,
+                }
+            }
+        }
+        Command::View { path, columns } => match load_pgm(path, &ParseOptions::default()) {
+            Ok((pgm, _)) if json => {
+                let rows = render(&pgm, *columns).into_iter().map(Json::from).collect();
+                let record = Json::Obj(vec![
+                    ("file", path.as_str().into()),
+                    ("rows", Json::Arr(rows)),
+                ]);
+                writeln!(output.out, "{}", record)?;
+            }
+            Ok((pgm, _)) => {
+                for row in render(&pgm, *columns) {
+                    writeln!(output.out, "{}", row)?;
+                }
+            }
+            Err(failure) => output.fail(path, failure)?,
         },
-    };
+        Command::Stats(path) => match load_pgm(path, &ParseOptions::default()) {
+            Ok((pgm, _)) => print_stats(output.out, json, path, &Stats::of(&pgm))?,
+            Err(failure) => output.fail(path, failure)?,
+        },
+    }
 
-    parser.parse(input)
+    Ok(output.code)
 }
 
-fn match_header_version(input: ParseInput) -> ParseResult<()> {
-    const VERSION_STR: &str = "P5";
+fn read_input(path: &str) -> io::Result<Vec<u8>> {
+    if path == "-" {
+        let mut input = vec![];
+        io::stdin().lock().read_to_end(&mut input)?;
+        Ok(input)
+    } else {
+        std::fs::read(path)
+    }
+}
 
-    if input.starts_with_str(VERSION_STR) {
-        // +1 is for the `\n` after the VERSION_STR
-        let read_until = VERSION_STR.len() + 1;
+/// Reads just the header of the PGM file at `path`, leaving the raster on disk.
+fn read_header(path: &str) -> Result<PgmHeader, Failure> {
+    if path == "-" {
+        let mut stdin = io::stdin().lock();
+        check_kind(stdin.fill_buf()?)?;
+        Ok(read_pgm_header_buffered(&mut stdin)?)
+    } else {
+        let mut file = std::fs::File::open(path)?;
+        let mut magic = vec![];
+        (&mut file).take(2).read_to_end(&mut magic)?;
+        check_kind(&magic)?;
+        file.rewind()?;
+        Ok(read_pgm_header(&mut file)?)
+    }
+}
 
-        Ok(((), &input[read_until..]))
+fn load_pgm(
+    path: &str,
+    options: &ParseOptions,
+) -> Result<(PGM<'static>, Vec<ParseError>), Failure> {
+    let input = read_input(path)?;
+    check_kind(&input)?;
+
+    match parse_pgm_with(&input, options) {
+        Ok(((pgm, warnings), _)) => Ok((pgm.into_owned(), warnings)),
+        Err((err, rest)) => {
+            let position = Position::locate(&input, rest);
+            Err(Failure::Parse(ParseError { err, position }, Some(input)))
+        }
+    }
+}
+
+fn print_header(out: &mut dyn Write, json: bool, path: &str, header: &PgmHeader) -> io::Result<()> {
+    if json {
+        let record = Json::Obj(vec![
+            ("file", path.into()),
+            ("format", pgm_magic(header.encoding).into()),
+            ("width", header.width.into()),
+            ("height", header.height.into()),
+            ("maxval", header.max_grey_val.into()),
+            ("raster_offset", header.raster_offset.into()),
+        ]);
+        writeln!(out, "{}", record)
     } else {
-        Err((ParseErr::NoHeaderMatch, input))
+        writeln!(
+            out,
+            "{}: {} {}x{}, maxval {}, raster at byte {}",
+            path,
+            pgm_magic(header.encoding),
+            header.width,
+            header.height,
+            header.max_grey_val,
+            header.raster_offset
+        )
     }
 }
 
-fn get_num(input: ParseInput) -> ParseResult<i32> {
-    let raw_num_str = input
-        .fields()
-        .next()
-        .ok_or_else(|| (ParseErr::NoValidFieldLeft, input))?;
+/// Draws `pgm` `columns` characters wide, going from dark to light. Characters being about
+/// twice as tall as they are wide, each line stands for twice as many rows as a column does.
+fn render(pgm: &PGM<'_>, columns: usize) -> Vec<String> {
+    const RAMP: &[u8] = b" .:-=+*#%@";
 
-    let num = raw_num_str.to_str().map_or_else(
-        |er| Err((ParseErr::Utf8Error(er), input)),
-        |s| {
-            s.parse::<i32>()
-                .or_else(|er| Err((ParseErr::InvalidNum(er), input)))
-        },
-    )?;
-
-    // `parsed_len` is length to consume after parse. The comparison is for "end of string" edge
-    // case.
-    let len = raw_num_str.len();
-    let parsed_len = match len.cmp(&input.len()) {
-        Ordering::Greater => panic!("Paradoxically parsed beyond string end"),
-        Ordering::Equal => len,
-        // +1 for skipping next whitespace (there should be always one is PGM specification)
-        Ordering::Less => len + 1,
-    };
+    let columns = columns.min(pgm.width);
+    if columns == 0 || pgm.height == 0 {
+        return vec![];
+    }
 
-    Ok((num, &input[parsed_len..]))
-}
+    let lines = (pgm.height * columns).div_ceil(2 * pgm.width);
+    let shade = |x, y| {
+        let sample = pgm.sample(x, y).unwrap_or_default() as usize;
+        RAMP[sample * (RAMP.len() - 1) / pgm.max_grey_val as usize] as char
+    };
 
-fn get_bytes(input: ParseInput, amount: usize) -> ParseResult<Vec<u8>> {
-    let parsed = <ParseInput as std::io::Read>::bytes(input)
-        .take(amount)
-        .fold(Ok(vec![]), |s, e| {
-            s.map(|mut s| {
-                e.map(|e| {
-                    s.push(e);
-                    s
-                })
-            })
-            .flatten()
+    (0..lines)
+        .map(|line| {
+            let y = (line * 2 * pgm.width / columns).min(pgm.height - 1);
+            (0..columns)
+                .map(|column| shade(column * pgm.width / columns, y))
+                .collect()
         })
-    .or_else(|er| Err((ParseErr::InvByte(er.to_string()), input)))?;
-
-    Ok((parsed, &input[amount..]))
+        .collect()
 }
 
-/********/
-/* Main */
-/********/
+/// Summary of the samples of an image; all `None` if it has none.
+#[derive(Debug, PartialEq)]
+struct Stats {
+    width: usize,
+    height: usize,
+    min: Option<u16>,
+    max: Option<u16>,
+    mean: Option<f64>,
+    std_dev: Option<f64>,
+}
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut file = std::fs::File::open("assets/lolcat.pgm")?;
+impl Stats {
+    fn of(pgm: &PGM<'_>) -> Stats {
+        let count = (pgm.width * pgm.height) as f64;
+        let (sum, sum_sq) = pgm.samples().fold((0.0, 0.0), |(sum, sum_sq), sample| {
+            let sample = f64::from(sample);
+            (sum + sample, sum_sq + sample * sample)
+        });
+        let mean = (count > 0.0).then(|| sum / count);
+
+        Stats {
+            width: pgm.width,
+            height: pgm.height,
+            min: pgm.samples().min(),
+            max: pgm.samples().max(),
+            mean,
+            std_dev: mean.map(|mean| (sum_sq / count - mean * mean).max(0.0).sqrt()),
+        }
+    }
+}
 
-    let mut contents = vec![];
-    file.read_to_end(&mut contents)?;
+fn print_stats(out: &mut dyn Write, json: bool, path: &str, stats: &Stats) -> io::Result<()> {
+    if json {
+        let record = Json::Obj(vec![
+            ("file", path.into()),
+            ("width", stats.width.into()),
+            ("height", stats.height.into()),
+            ("min", stats.min.into()),
+            ("max", stats.max.into()),
+            ("mean", stats.mean.map(Json::Num).into()),
+            ("std_dev", stats.std_dev.map(Json::Num).into()),
+        ]);
+        return writeln!(out, "{}", record);
+    }
 
-    let contents = bstr::BString::from(contents);
-    let pgm = parse_pgm(contents.as_slice());
+    write!(out, "{}: {}x{}", path, stats.width, stats.height)?;
+    if let (Some(min), Some(max), Some(mean), Some(std_dev)) =
+        (stats.min, stats.max, stats.mean, stats.std_dev)
+    {
+        write!(
+            out,
+            ", min {}, max {}, mean {:.2}, std dev {:.2}",
+            min, max, mean, std_dev
+        )?;
+    }
+    writeln!(out)
+}
 
-    println!("{:?}", pgm);
+fn main() {
+    let cli = match parse_args(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(problem) => {
+            eprintln!("{}\n\n{}", problem, USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    Ok(())
+    let (stdout, stderr) = (io::stdout(), io::stderr());
+    let code = run(&cli, &mut stdout.lock(), &mut stderr.lock()).unwrap_or_else(|er| {
+        eprintln!("{}", er);
+        3
+    });
+    std::process::exit(code);
 }
 
 /*********/
@@ -196,41 +667,128 @@ fn main() -> Result<(), Box<dyn Error>> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use indoc::indoc;
 
-    #[test]
-    fn match_header_version_obv() {
-        let mock_header: &[u8] = indoc!(
-            "P5
-            120 32"
-        )
-            .as_bytes();
+    fn args(line: &str) -> Result<Cli, String> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
 
+    #[test]
+    fn parse_command_lines() {
+        assert_eq!(
+            args("--json convert --plain in.pgm --line-width 20"),
+            Ok(Cli {
+                json: true,
+                command: Command::Convert {
+                    input: "in.pgm".to_string(),
+                    output: "-".to_string(),
+                    encoding: Encoding::Plain,
+                    line_width: Some(20),
+                },
+            })
+        );
+        assert_eq!(
+            args("info").map(|cli| cli.command),
+            Ok(Command::Info(vec!["-".to_string()]))
+        );
         assert_eq!(
-            match match_header_version(mock_header) {
-                Ok(((), s)) => Ok(((), s.as_bstr())),
-                Err((er, s)) => Err((er, s.as_bstr())),
-            },
-            Ok(((), "120 32".as_bytes().as_bstr())),
+            args("view a.pgm").map(|cli| cli.command),
+            Ok(Command::View {
+                path: "a.pgm".to_string(),
+                columns: 80,
+            })
         );
+
+        assert!(args("").is_err());
+        assert!(args("frobnicate").is_err());
+        assert!(args("stats a.pgm b.pgm").is_err());
+        assert!(args("validate --plain").is_err());
+        assert!(args("view --columns").is_err());
+        assert!(args("view --columns many").is_err());
     }
 
     #[test]
-    fn get_num_single() {
-        assert_eq!(get_num("12".as_bytes()), Ok((12, "".as_bytes())));
+    fn json_escapes_strings() {
+        let record = Json::Obj(vec![
+            ("file", "a \"b\"\\\n\u{1}".into()),
+            (
+                "sizes",
+                Json::Arr(vec![1usize.into(), Json::Num(0.5), Json::Null]),
+            ),
+        ]);
+
+        assert_eq!(
+            record.to_string(),
+            r#"{"file":"a \"b\"\\\n\u0001","sizes":[1,0.5,null]}"#
+        );
     }
 
-    #[test]
-    fn get_num_multiple() {
-        assert_eq!(get_num("12 24".as_bytes()), Ok((12, "24".as_bytes())));
+    fn run_line(line: &str) -> (i32, String, String) {
+        let (mut out, mut errs) = (vec![], vec![]);
+        let code = run(&args(line).unwrap(), &mut out, &mut errs).unwrap();
+
+        (
+            code,
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(errs).unwrap(),
+        )
     }
 
     #[test]
-    fn and_then_map_2_nums() {
-        let input = b"12 14 16";
-
-        let res = and_then(get_num, move |n1| map(get_num, move |n2| (n1, n2))).parse(input);
+    fn run_commands() {
+        assert_eq!(
+            run_line("--json info assets/lolcat.pgm"),
+            (
+                0,
+                concat!(
+                    r#"{"file":"assets/lolcat.pgm","format":"P5","width":430,"height":430,"#,
+                    r#""maxval":255,"raster_offset":15}"#,
+                    "\n"
+                )
+                .to_string(),
+                String::new()
+            )
+        );
 
-        assert_eq!(res, Ok(((12, 14), "16".as_bytes())),);
+        let (code, out, _) = run_line("stats assets/lolcat.pgm");
+        assert_eq!(code, 0);
+        assert!(out.starts_with("assets/lolcat.pgm: 430x430, min 0, max 255, mean "));
+
+        let (code, out, _) = run_line("view --columns 43 assets/lolcat.pgm");
+        assert_eq!(code, 0);
+        assert_eq!(out.lines().count(), 22);
+        assert!(out.lines().all(|line| line.len() == 43));
+
+        // Named after the process, so that concurrent runs don't trip over each other's file.
+        let file = format!("rwh-ch10-rust-truncated-{}.pgm", std::process::id());
+        let file = std::env::temp_dir().join(file);
+        std::fs::write(&file, b"P5 2 2 255\n\x01").unwrap();
+        let path = file.to_str().unwrap();
+
+        let (code, out, errs) = run_line(&format!("validate {} assets/lolcat.pgm", path));
+        assert_eq!(code, 5);
+        assert_eq!(out, "assets/lolcat.pgm: ok\n");
+        assert!(errs.contains("unexpected end of input: needed 4, only 1 available"));
+
+        let (code, _, errs) = run_line(&format!("validate --lenient {}", path));
+        assert_eq!(code, 0);
+        assert!(errs.contains("warning"));
+        std::fs::remove_file(&file).unwrap();
+
+        let file = format!("rwh-ch10-rust-color-{}.ppm", std::process::id());
+        let file = std::env::temp_dir().join(file);
+        std::fs::write(&file, b"P6 1 1 255\n\x01\x02\x03").unwrap();
+        let path = file.to_str().unwrap();
+
+        for command in &["info", "validate", "stats"] {
+            let (code, _, errs) = run_line(&format!("{} {}", command, path));
+            assert_eq!(code, 8);
+            assert!(errs.contains("PPM images are not supported, only PGM ones"));
+        }
+        let (code, out, _) = run_line(&format!("--json info {}", path));
+        assert_eq!(code, 8);
+        assert!(out.contains(r#""kind":"unsupported""#));
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(run_line("stats /nonexistent.pgm").0, 3);
     }
 }
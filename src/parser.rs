@@ -0,0 +1,1008 @@
+//! Parser combinators over byte slices, along with the errors they report.
+
+use bstr::ByteSlice;
+use std::error::Error;
+use std::fmt;
+
+/********************************************/
+/* Parser general definition and properties */
+/********************************************/
+
+pub type ParseInput<'a> = &'a [u8];
+pub type ParseResult<'a, T> = Result<(T, ParseInput<'a>), (ParseErr, ParseInput<'a>)>;
+
+pub trait Parser<'a, Out> {
+    fn parse(&self, input: ParseInput<'a>) -> ParseResult<'a, Out>;
+
+    /// Transforms the output with `fun`.
+    fn map<Out1, F>(self, fun: F) -> impl Parser<'a, Out1>
+    where
+        Self: Sized,
+        F: Fn(Out) -> Out1,
+    {
+        move |input| self.parse(input).map(|(out, rest)| (fun(out), rest))
+    }
+
+    /// Continues with the parser `fun` builds out of the output.
+    fn and_then<Out1, F, P1>(self, fun: F) -> impl Parser<'a, Out1>
+    where
+        Self: Sized,
+        F: Fn(Out) -> P1,
+        P1: Parser<'a, Out1>,
+    {
+        move |input| {
+            self.parse(input)
+                .and_then(|(out, rest)| fun(out).parse(rest))
+        }
+    }
+
+    /// Continues with `next`, keeping only its output.
+    fn then<Out1, P1>(self, next: P1) -> impl Parser<'a, Out1>
+    where
+        Self: Sized,
+        P1: Parser<'a, Out1>,
+    {
+        move |input| {
+            let (_, rest) = self.parse(input)?;
+            next.parse(rest)
+        }
+    }
+
+    /// Continues with `next`, keeping only the output of `self`.
+    fn skip<Out1, P1>(self, next: P1) -> impl Parser<'a, Out>
+    where
+        Self: Sized,
+        P1: Parser<'a, Out1>,
+    {
+        move |input| {
+            let (out, rest) = self.parse(input)?;
+            let (_, rest) = next.parse(rest)?;
+            Ok((out, rest))
+        }
+    }
+
+    /// Succeeds with `None`, consuming nothing, where `self` fails.
+    fn optional(self) -> impl Parser<'a, Option<Out>>
+    where
+        Self: Sized,
+    {
+        move |input| match self.parse(input) {
+            Ok((out, rest)) => Ok((Some(out), rest)),
+            Err(_) => Ok((None, input)),
+        }
+    }
+
+    /// Reports failures that consumed nothing as expecting `what`, rather than whatever `self`
+    /// expected.
+    fn label(self, what: &'static str) -> impl Parser<'a, Out>
+    where
+        Self: Sized,
+    {
+        move |input: ParseInput<'a>| {
+            self.parse(input).map_err(|(er, rest)| {
+                if rest.len() == input.len() {
+                    (ParseErr::Expected(vec![what.to_string()]), rest)
+                } else {
+                    (er, rest)
+                }
+            })
+        }
+    }
+
+    /// Wraps failures in `ParseErr::Context`, telling what was being parsed when they happened.
+    fn context(self, label: &'static str) -> impl Parser<'a, Out>
+    where
+        Self: Sized,
+    {
+        move |input| {
+            self.parse(input)
+                .map_err(|(er, rest)| (ParseErr::Context(label, Box::new(er)), rest))
+        }
+    }
+
+    /// Fails, expecting `what`, unless `pred` holds on the output.
+    fn verify<F>(self, what: &'static str, pred: F) -> impl Parser<'a, Out>
+    where
+        Self: Sized,
+        F: Fn(&Out) -> bool,
+    {
+        move |input| match self.parse(input)? {
+            (out, rest) if pred(&out) => Ok((out, rest)),
+            _ => Err((ParseErr::Expected(vec![what.to_string()]), input)),
+        }
+    }
+}
+
+impl<'a, Out, F> Parser<'a, Out> for F
+where
+    F: Fn(ParseInput<'a>) -> ParseResult<Out>,
+{
+    fn parse(&self, input: ParseInput<'a>) -> ParseResult<'a, Out> {
+        self(input)
+    }
+}
+
+pub fn map<'a, Out, Out1, F, P>(parser: P, fun: F) -> impl Parser<'a, Out1>
+where
+    F: Fn(Out) -> Out1,
+    P: Parser<'a, Out>,
+{
+    parser.map(fun)
+}
+
+pub fn and_then<'a, Out, Out1, F, P, P1>(parser: P, fun: F) -> impl Parser<'a, Out1>
+where
+    F: Fn(Out) -> P1,
+    P: Parser<'a, Out>,
+    P1: Parser<'a, Out1>,
+{
+    parser.and_then(fun)
+}
+
+/// `parser`, failing with what it was parsing as `label`. Nested contexts stack up, see
+/// `ParseErr::Context`.
+pub fn context<'a, Out, P>(label: &'static str, parser: P) -> impl Parser<'a, Out>
+where
+    P: Parser<'a, Out>,
+{
+    parser.context(label)
+}
+
+/// Tries `parser`, and `other` from the same input if it fails.
+pub fn or<'a, Out, P, P1>(parser: P, other: P1) -> impl Parser<'a, Out>
+where
+    P: Parser<'a, Out>,
+    P1: Parser<'a, Out>,
+{
+    move |input| {
+        parser
+            .parse(input)
+            .or_else(|er| other.parse(input).map_err(|er1| merge_errs(er, er1)))
+    }
+}
+
+/// Tries each of `parsers` in turn from the same input, until one succeeds.
+pub fn choice<'a, Out, P>(parsers: Vec<P>) -> impl Parser<'a, Out>
+where
+    P: Parser<'a, Out>,
+{
+    move |input| {
+        let mut failure = (ParseErr::Expected(vec![]), input);
+
+        for parser in &parsers {
+            match parser.parse(input) {
+                Ok(parsed) => return Ok(parsed),
+                Err(er) => failure = merge_errs(failure, er),
+            }
+        }
+
+        Err(failure)
+    }
+}
+
+/// Applies `parser` as many times as it succeeds, combining its outputs with `fun` starting from
+/// `init()`. A success that consumes nothing ends the repetition (and is discarded), so this
+/// always terminates.
+pub fn fold_many<'a, Out, Acc, P, I, F>(parser: P, init: I, fun: F) -> impl Parser<'a, Acc>
+where
+    P: Parser<'a, Out>,
+    I: Fn() -> Acc,
+    F: Fn(Acc, Out) -> Acc,
+{
+    move |input| Ok(repeat(&parser, init(), &fun, input))
+}
+
+/// Applies `parser` as many times as it succeeds, see `fold_many`.
+pub fn many<'a, Out, P>(parser: P) -> impl Parser<'a, Vec<Out>>
+where
+    P: Parser<'a, Out>,
+{
+    fold_many(parser, Vec::new, push)
+}
+
+/// Like `many`, but fails unless `parser` succeeds at least once.
+pub fn many1<'a, Out, P>(parser: P) -> impl Parser<'a, Vec<Out>>
+where
+    P: Parser<'a, Out>,
+{
+    move |input| {
+        let (first, rest) = parser.parse(input)?;
+        Ok(repeat(&parser, vec![first], &push, rest))
+    }
+}
+
+/// Zero or more `parser`s, separated by `sep`s. A trailing `sep` is left unconsumed.
+pub fn sep_by<'a, Out, Sep, P, S>(parser: P, sep: S) -> impl Parser<'a, Vec<Out>>
+where
+    P: Parser<'a, Out>,
+    S: Parser<'a, Sep>,
+{
+    move |input| match parser.parse(input) {
+        Ok((first, rest)) => {
+            let sep_then_parser = |i: ParseInput<'a>| {
+                let (_, rest) = sep.parse(i)?;
+                parser.parse(rest)
+            };
+            Ok(repeat(&sep_then_parser, vec![first], &push, rest))
+        }
+        Err(_) => Ok((vec![], input)),
+    }
+}
+
+/// Exactly `n` `parser`s in a row.
+pub fn count<'a, Out, P>(n: usize, parser: P) -> impl Parser<'a, Vec<Out>>
+where
+    P: Parser<'a, Out>,
+{
+    move |input| {
+        (0..n).try_fold((Vec::with_capacity(n), input), |(outs, rest), _| {
+            let (out, rest) = parser.parse(rest)?;
+            Ok((push(outs, out), rest))
+        })
+    }
+}
+
+fn push<T>(mut vec: Vec<T>, elem: T) -> Vec<T> {
+    vec.push(elem);
+    vec
+}
+
+/// Loop behind the repetition combinators.
+fn repeat<'a, Out, Acc, P, F>(
+    parser: &P,
+    mut acc: Acc,
+    fun: &F,
+    input: ParseInput<'a>,
+) -> (Acc, ParseInput<'a>)
+where
+    P: Parser<'a, Out>,
+    F: Fn(Acc, Out) -> Acc,
+{
+    let mut rest = input;
+
+    while let Ok((out, new_rest)) = parser.parse(rest) {
+        if new_rest.len() == rest.len() {
+            break;
+        }
+
+        acc = fun(acc, out);
+        rest = new_rest;
+    }
+
+    (acc, rest)
+}
+
+/// Combines the failures of two alternatives. The one that got further wins; when both stopped
+/// at the same point, what each of them expected there is merged, and otherwise an actual error
+/// beats a mere expectation.
+fn merge_errs<'a>(
+    er: (ParseErr, ParseInput<'a>),
+    er1: (ParseErr, ParseInput<'a>),
+) -> (ParseErr, ParseInput<'a>) {
+    match er.1.len().cmp(&er1.1.len()) {
+        std::cmp::Ordering::Less => er,
+        std::cmp::Ordering::Greater => er1,
+        std::cmp::Ordering::Equal => match (er, er1) {
+            ((ParseErr::Expected(mut expected), rest), (ParseErr::Expected(expected1), _)) => {
+                expected.extend(expected1);
+                (ParseErr::Expected(expected), rest)
+            }
+            ((ParseErr::Expected(_), _), er1) => er1,
+            (er, _) => er,
+        },
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseErr {
+    NoValidFieldLeft,
+    /// None of the alternatives that were tried matched.
+    Expected(Vec<String>),
+    Utf8Error(bstr::Utf8Error),
+    InvalidNum(std::num::ParseIntError),
+    InvalidBit(u8),
+    UnknownPamKeyword(String),
+    UnknownTupleType(String),
+    MissingPamField(&'static str),
+    /// The tuple type, and the `DEPTH` given for it.
+    TupleDepthMismatch(&'static str, usize),
+    /// The input ends before the raster does. Counted in bytes for raw rasters and in samples
+    /// (pixels for PBM) for plain ones.
+    UnexpectedEof {
+        needed: usize,
+        available: usize,
+    },
+    /// Width and height cannot be negative.
+    InvalidDimensions {
+        width: i32,
+        height: i32,
+    },
+    /// The `DEPTH` of a PAM file cannot be negative.
+    InvalidDepth(i32),
+    /// Maxvals go from 1 to 65535.
+    InvalidMaxval(i32),
+    /// A sample above the maxval, at column `x` and row `y` of the image.
+    SampleExceedsMaxval {
+        x: usize,
+        y: usize,
+        value: u16,
+    },
+    /// The image is bigger than `ParseLimits` allow: what is limited, and the limit.
+    LimitExceeded {
+        limit: &'static str,
+        max: usize,
+    },
+    /// The error, and what was being parsed when it happened, as labelled by `context`. Contexts
+    /// nest outermost first, rendering as "while parsing PGM header > height: ...".
+    Context(&'static str, Box<ParseErr>),
+}
+
+impl ParseErr {
+    /// The error itself, without the contexts it happened in.
+    pub fn root(&self) -> &ParseErr {
+        match self {
+            ParseErr::Context(_, er) => er.root(),
+            er => er,
+        }
+    }
+}
+
+/// Where a parser stopped, relative to the start of the whole input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    /// Bytes consumed since the start of the input.
+    pub offset: usize,
+    /// Line and column, both starting from 1, as seen when viewing the (text) header.
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// Position of `rest` in `input`. Since parsers only ever consume from the front, what they
+    /// leave (and report in errors) is always a suffix of their input.
+    pub fn locate(input: ParseInput, rest: ParseInput) -> Position {
+        let offset = input.len() - rest.len();
+        let consumed = &input[..offset];
+        let line_start = consumed.rfind_byte(b'\n').map_or(0, |newline| newline + 1);
+
+        Position {
+            offset,
+            line: consumed.find_iter(b"\n").count() + 1,
+            column: offset - line_start + 1,
+        }
+    }
+}
+
+/// A `ParseErr` along with the position it occurred at.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub err: ParseErr,
+    pub position: Position,
+}
+
+/// Runs `parser` on `input`, turning the remaining input of a failure into its position.
+pub fn parse<'a, Out, P>(
+    parser: P,
+    input: ParseInput<'a>,
+) -> Result<(Out, ParseInput<'a>), ParseError>
+where
+    P: Parser<'a, Out>,
+{
+    parser.parse(input).map_err(|(err, rest)| ParseError {
+        err,
+        position: Position::locate(input, rest),
+    })
+}
+
+/// Chains parsers in the style of Haskell's do notation. The statements are:
+///
+/// - `pattern <- parser,` runs `parser` and binds its output to `pattern`, which can be a name,
+///   `_`, or a tuple or struct pattern, for the rest of the chain.
+/// - `parser,` runs `parser`, ignoring its output.
+/// - `let pattern = expr;` binds `expr` without touching the input.
+/// - `guard cond else err;` fails with `err` where the chain got to, unless `cond` holds.
+///
+/// The chain ends with either `return expr,`, succeeding with `expr` without consuming
+/// anything, or `yield parser,`, running `parser` as the last step and succeeding with its
+/// output.
+#[macro_export]
+macro_rules! parse_do {
+    (return $val:expr $(,)?) => {
+        move |input| Ok(($val, input))
+    };
+    (yield $parser:expr $(,)?) => {
+        $parser
+    };
+    (let $pat:pat = $val:expr; $($tail:tt)*) => {{
+        let $pat = $val;
+        $crate::parse_do!($($tail)*)
+    }};
+    (guard $($tail:tt)*) => {
+        $crate::parse_do!(@guard [] $($tail)*)
+    };
+    // Conditions can't be matched as an `expr` since one can't be followed by `else`, so their
+    // tokens are gathered one at a time until it shows up.
+    (@guard [$($cond:tt)*] else $err:expr; $($tail:tt)*) => {
+        move |input| {
+            if $($cond)* {
+                $crate::parser::Parser::parse(&$crate::parse_do!($($tail)*), input)
+            } else {
+                Err(($err, input))
+            }
+        }
+    };
+    (@guard [$($cond:tt)*] $token:tt $($tail:tt)*) => {
+        $crate::parse_do!(@guard [$($cond)* $token] $($tail)*)
+    };
+    // Likewise for patterns and `<-`, telling a bind apart from a parser run for its effect.
+    (@step [$($pat:tt)*] <- $parser:expr, $($tail:tt)*) => {
+        $crate::parser::and_then($parser, move |$($pat)*| $crate::parse_do!($($tail)*))
+    };
+    (@step [$($parser:tt)*], $($tail:tt)*) => {
+        $crate::parser::and_then($($parser)*, move |_| $crate::parse_do!($($tail)*))
+    };
+    (@step [$($step:tt)*] $token:tt $($tail:tt)*) => {
+        $crate::parse_do!(@step [$($step)* $token] $($tail)*)
+    };
+    ($($tail:tt)+) => {
+        $crate::parse_do!(@step [] $($tail)*)
+    };
+}
+
+/*********************/
+/* Primitive parsers */
+/*********************/
+
+/// The exact bytes of `tag`.
+pub fn tag<'a>(tag: &'static str) -> impl Parser<'a, &'a [u8]> {
+    move |input: ParseInput<'a>| {
+        if input.starts_with_str(tag) {
+            Ok(input.split_at(tag.len()))
+        } else {
+            Err((ParseErr::Expected(vec![tag.to_string()]), input))
+        }
+    }
+}
+
+/// A byte for which `pred` holds, described as `what` in errors.
+pub fn satisfy<'a, F>(what: &'static str, pred: F) -> impl Parser<'a, u8>
+where
+    F: Fn(u8) -> bool,
+{
+    move |input: ParseInput<'a>| match input.split_first() {
+        Some((&b, rest)) if pred(b) => Ok((b, rest)),
+        Some(_) => Err((ParseErr::Expected(vec![what.to_string()]), input)),
+        None => Err((ParseErr::NoValidFieldLeft, input)),
+    }
+}
+
+/// The byte `b`.
+pub fn byte<'a>(b: u8) -> impl Parser<'a, u8> {
+    move |input: ParseInput<'a>| match input.split_first() {
+        Some((&found, rest)) if found == b => Ok((b, rest)),
+        Some(_) => Err((
+            ParseErr::Expected(vec![format!("'{}'", b.escape_ascii())]),
+            input,
+        )),
+        None => Err((ParseErr::NoValidFieldLeft, input)),
+    }
+}
+
+/// The next `n` bytes.
+pub fn take<'a>(n: usize) -> impl Parser<'a, &'a [u8]> {
+    move |input: ParseInput<'a>| {
+        if input.len() >= n {
+            Ok(input.split_at(n))
+        } else {
+            let available = input.len();
+            Err((
+                ParseErr::UnexpectedEof {
+                    needed: n,
+                    available,
+                },
+                input,
+            ))
+        }
+    }
+}
+
+/// The longest run of bytes for which `pred` holds, possibly empty.
+pub fn take_while<'a, F>(pred: F) -> impl Parser<'a, &'a [u8]>
+where
+    F: Fn(u8) -> bool,
+{
+    move |input: ParseInput<'a>| {
+        let len = input.iter().take_while(|&&b| pred(b)).count();
+        Ok(input.split_at(len))
+    }
+}
+
+/// Like `take_while`, but fails unless at least one byte matches.
+pub fn take_while1<'a, F>(what: &'static str, pred: F) -> impl Parser<'a, &'a [u8]>
+where
+    F: Fn(u8) -> bool,
+{
+    let parser = take_while(pred);
+
+    move |input: ParseInput<'a>| match parser.parse(input)? {
+        ([], _) if input.is_empty() => Err((ParseErr::NoValidFieldLeft, input)),
+        ([], _) => Err((ParseErr::Expected(vec![what.to_string()]), input)),
+        parsed => Ok(parsed),
+    }
+}
+
+/// A single whitespace byte.
+pub fn whitespace(input: ParseInput) -> ParseResult<u8> {
+    satisfy("whitespace", |b| b.is_ascii_whitespace()).parse(input)
+}
+
+/// One or more whitespace bytes.
+pub fn whitespace1<'a>(input: ParseInput<'a>) -> ParseResult<'a, &'a [u8]> {
+    take_while1("whitespace", |b| b.is_ascii_whitespace()).parse(input)
+}
+
+/// The end of the input.
+pub fn eof(input: ParseInput) -> ParseResult<()> {
+    if input.is_empty() {
+        Ok(((), input))
+    } else {
+        Err((ParseErr::Expected(vec!["end of input".to_string()]), input))
+    }
+}
+
+/*******************/
+/* Error reporting */
+/*******************/
+
+impl fmt::Display for ParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErr::NoValidFieldLeft => write!(f, "unexpected end of input"),
+            ParseErr::Expected(expected) => match expected.split_last() {
+                None => write!(f, "unexpected input"),
+                Some((last, [])) => write!(f, "expected {}", last),
+                Some((last, init)) => write!(f, "expected {} or {}", init.join(", "), last),
+            },
+            ParseErr::Utf8Error(_) => write!(f, "field is not valid UTF-8"),
+            ParseErr::InvalidNum(_) => write!(f, "invalid number"),
+            ParseErr::InvalidBit(bit) => write!(f, "invalid bit {:?}", [*bit].as_bstr()),
+            ParseErr::UnknownPamKeyword(keyword) => write!(f, "unknown PAM keyword {:?}", keyword),
+            ParseErr::UnknownTupleType(name) => write!(f, "unsupported tuple type {:?}", name),
+            ParseErr::MissingPamField(field) => write!(f, "missing {} in PAM header", field),
+            ParseErr::TupleDepthMismatch(tuple_type, depth) => {
+                write!(f, "tuple type {} does not have depth {}", tuple_type, depth)
+            }
+            ParseErr::UnexpectedEof { needed, available } => write!(
+                f,
+                "unexpected end of input: needed {}, only {} available",
+                needed, available
+            ),
+            ParseErr::InvalidDimensions { width, height } => {
+                write!(f, "invalid dimensions {}x{}", width, height)
+            }
+            ParseErr::InvalidDepth(depth) => write!(f, "invalid depth {}", depth),
+            ParseErr::InvalidMaxval(max_val) => write!(f, "invalid maxval {}", max_val),
+            ParseErr::SampleExceedsMaxval { x, y, value } => {
+                write!(f, "sample {} at ({}, {}) exceeds the maxval", value, x, y)
+            }
+            ParseErr::LimitExceeded { limit, max } => {
+                write!(f, "{} exceeds the limit of {}", limit, max)
+            }
+            ParseErr::Context(label, er) => {
+                write!(f, "while parsing {}", label)?;
+
+                let mut er = er;
+                while let ParseErr::Context(label, inner) = &**er {
+                    write!(f, " > {}", label)?;
+                    er = inner;
+                }
+                write!(f, ": {}", er)
+            }
+        }
+    }
+}
+
+impl Error for ParseErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseErr::Utf8Error(er) => Some(er),
+            ParseErr::InvalidNum(er) => Some(er),
+            ParseErr::Context(_, er) => er.source(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {} (byte {})",
+            self.line, self.column, self.offset
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.err, self.position)
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        // `err` is already part of the message, so skip to what caused it.
+        self.err.source()
+    }
+}
+
+impl ParseError {
+    /// Renders the error for humans: its causes, then the offending line of `input` (the input
+    /// it was located in) with the failing token underlined.
+    pub fn report<'a>(&'a self, input: ParseInput<'a>) -> Report<'a> {
+        Report { err: self, input }
+    }
+}
+
+/// See `ParseError::report`.
+pub struct Report<'a> {
+    err: &'a ParseError,
+    input: ParseInput<'a>,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.err)?;
+
+        let mut source = self.err.source();
+        while let Some(er) = source {
+            write!(f, ": {}", er)?;
+            source = er.source();
+        }
+
+        let Position { offset, column, .. } = self.err.position;
+        let line_start = offset + 1 - column;
+        let line = self.input[line_start..].lines().next().unwrap_or_default();
+
+        // Errors in a raw raster point at binary data, which is of no use to print.
+        if !line
+            .iter()
+            .all(|&b| b == b'\t' || (b' '..=b'~').contains(&b))
+        {
+            return Ok(());
+        }
+
+        let token_len = self.input[offset..]
+            .iter()
+            .take_while(|b| !b.is_ascii_whitespace())
+            .count();
+        let gutter = " ".repeat(self.err.position.line.to_string().len());
+
+        // Tabs are kept as they are, so that the caret lines up however wide they show.
+        let padding: String = line
+            .iter()
+            .take(column - 1)
+            .map(|&b| if b == b'\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.err.position.line, line.as_bstr())?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            padding,
+            "^".repeat(token_len.max(1))
+        )
+    }
+}
+
+/***********/
+/* Tracing */
+/***********/
+
+/// `parser`, logging to stderr where it starts and how much it consumes, or where and why it
+/// fails. Traced parsers nested in one another are indented accordingly, and offsets count from
+/// the input of the outermost one.
+///
+/// Only logs with the `trace` feature on; without it this is `parser` itself.
+#[cfg(feature = "trace")]
+pub fn traced<'a, Out, P>(name: &'static str, parser: P) -> impl Parser<'a, Out>
+where
+    P: Parser<'a, Out>,
+{
+    use std::cell::Cell;
+
+    thread_local! {
+        /// How many traced parsers are running, and where the input of the outermost one starts.
+        static TRACE: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    }
+
+    move |input: ParseInput<'a>| {
+        let (depth, start) = TRACE.with(Cell::get);
+        let start = if depth == 0 {
+            input.as_ptr() as usize
+        } else {
+            start
+        };
+        let offset = |rest: ParseInput| (rest.as_ptr() as usize).wrapping_sub(start);
+        let indent = "  ".repeat(depth);
+
+        eprintln!("{}{} @{}", indent, name, offset(input));
+        TRACE.with(|trace| trace.set((depth + 1, start)));
+        let result = parser.parse(input);
+        TRACE.with(|trace| trace.set((depth, start)));
+
+        match &result {
+            Ok((_, rest)) => eprintln!(
+                "{}{} ok, consumed {} bytes",
+                indent,
+                name,
+                input.len() - rest.len()
+            ),
+            Err((err, rest)) => eprintln!("{}{} failed @{}: {}", indent, name, offset(rest), err),
+        }
+        result
+    }
+}
+
+/// `parser` itself, since logging what it does needs the `trace` feature.
+#[cfg(not(feature = "trace"))]
+pub fn traced<'a, Out, P>(_name: &'static str, parser: P) -> impl Parser<'a, Out>
+where
+    P: Parser<'a, Out>,
+{
+    parser
+}
+
+/*********/
+/* Tests */
+/*********/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whitespace, if any.
+    fn blank(input: ParseInput) -> ParseResult<()> {
+        take_while(|b| b.is_ascii_whitespace())
+            .map(|_| ())
+            .parse(input)
+    }
+
+    /// A field running up to the next whitespace, which is skipped as well.
+    fn field<'a>(input: ParseInput<'a>) -> ParseResult<'a, &'a [u8]> {
+        take_while1("a field", |b| !b.is_ascii_whitespace())
+            .skip(blank)
+            .parse(input)
+    }
+
+    /// A field holding a decimal number.
+    fn num(input: ParseInput) -> ParseResult<i32> {
+        let (digits, rest) = field(input)?;
+        let n = String::from_utf8_lossy(digits)
+            .parse()
+            .map_err(|er| (ParseErr::InvalidNum(er), input))?;
+
+        Ok((n, rest))
+    }
+
+    #[test]
+    fn or_backtracks() {
+        let parser = or(and_then(num, |_| num), num);
+
+        assert_eq!(parser.parse(b"12 x"), Ok((12, "x".as_bytes())));
+    }
+
+    #[test]
+    fn or_keeps_furthest_error() {
+        assert!(matches!(
+            or(and_then(num, |_| num), num).parse(b"x 1 x"),
+            Err((ParseErr::InvalidNum(_), rest)) if rest == b"x 1 x"
+        ));
+        assert!(matches!(
+            or(num, and_then(field, |_| num)).parse(b"x y"),
+            Err((ParseErr::InvalidNum(_), rest)) if rest == b"y"
+        ));
+    }
+
+    #[test]
+    fn many_collects_until_failure() {
+        assert_eq!(
+            many(num).parse(b"1 2 3 x"),
+            Ok((vec![1, 2, 3], "x".as_bytes()))
+        );
+        assert_eq!(many(num).parse(b"x"), Ok((vec![], "x".as_bytes())));
+        assert!(matches!(
+            many1(num).parse(b"x"),
+            Err((ParseErr::InvalidNum(_), _))
+        ));
+        assert_eq!(many1(num).parse(b"1 x"), Ok((vec![1], "x".as_bytes())));
+    }
+
+    #[test]
+    fn many_terminates_on_empty_match() {
+        assert_eq!(many(blank).parse(b"  P2"), Ok((vec![()], "P2".as_bytes())));
+        assert_eq!(many(blank).parse(b"P2"), Ok((vec![], "P2".as_bytes())));
+    }
+
+    #[test]
+    fn fold_many_sums() {
+        let sum = fold_many(num, || 0, |acc, n| acc + n);
+
+        assert_eq!(sum.parse(b"1 2 3"), Ok((6, "".as_bytes())));
+    }
+
+    #[test]
+    fn sep_by_leaves_trailing_separator() {
+        fn comma(input: ParseInput) -> ParseResult<()> {
+            match input.first() {
+                Some(b',') => Ok(((), &input[1..])),
+                _ => Err((ParseErr::Expected(vec![",".to_string()]), input)),
+            }
+        }
+        let numbers = sep_by(num, comma);
+
+        assert_eq!(numbers.parse(b"1 ,2 ,x"), Ok((vec![1, 2], ",x".as_bytes())));
+        assert_eq!(numbers.parse(b"x"), Ok((vec![], "x".as_bytes())));
+    }
+
+    #[test]
+    fn count_exactly() {
+        assert_eq!(
+            count(2, num).parse(b"1 2 3"),
+            Ok((vec![1, 2], "3".as_bytes()))
+        );
+        assert!(matches!(
+            count(3, num).parse(b"1 2"),
+            Err((ParseErr::NoValidFieldLeft, _))
+        ));
+    }
+
+    #[test]
+    fn primitives() {
+        assert_eq!(
+            tag("P7").parse(b"P7\n"),
+            Ok(("P7".as_bytes(), "\n".as_bytes()))
+        );
+        assert_eq!(byte(b'#').parse(b"#!"), Ok((b'#', "!".as_bytes())));
+        assert_eq!(
+            satisfy("a digit", |b| b.is_ascii_digit()).parse(b"x"),
+            Err((
+                ParseErr::Expected(vec!["a digit".to_string()]),
+                "x".as_bytes()
+            ))
+        );
+        assert_eq!(take(2).parse(b"abc"), Ok(("ab".as_bytes(), "c".as_bytes())));
+        assert_eq!(
+            take(4).parse(b"abc"),
+            Err((
+                ParseErr::UnexpectedEof {
+                    needed: 4,
+                    available: 3
+                },
+                "abc".as_bytes()
+            ))
+        );
+        assert_eq!(
+            take_while(|b| b == b'a').parse(b"bc"),
+            Ok(("".as_bytes(), "bc".as_bytes()))
+        );
+        assert_eq!(
+            whitespace1(b"x"),
+            Err((
+                ParseErr::Expected(vec!["whitespace".to_string()]),
+                "x".as_bytes()
+            ))
+        );
+        assert_eq!(whitespace1(b" \tx"), Ok((" \t".as_bytes(), "x".as_bytes())));
+        assert_eq!(eof(b""), Ok(((), "".as_bytes())));
+        assert!(eof(b"x").is_err());
+    }
+
+    #[test]
+    fn parser_ext_reads_left_to_right() {
+        let pair = num.and_then(move |n1| num.map(move |n2| (n1, n2)));
+        assert_eq!(pair.parse(b"12 14 16"), Ok(((12, 14), "16".as_bytes())));
+
+        let second = num.then(num);
+        assert_eq!(second.parse(b"12 14"), Ok((14, "".as_bytes())));
+
+        let first = num.skip(byte(b','));
+        assert_eq!(first.parse(b"12 ,"), Ok((12, "".as_bytes())));
+
+        let maybe = num.optional();
+        assert_eq!(maybe.parse(b"x"), Ok((None, "x".as_bytes())));
+    }
+
+    #[test]
+    fn label_and_verify() {
+        let width = num.label("a width");
+        assert_eq!(
+            width.parse(b"x"),
+            Err((
+                ParseErr::Expected(vec!["a width".to_string()]),
+                "x".as_bytes()
+            ))
+        );
+
+        let even = num.verify("an even number", |n| n % 2 == 0);
+        assert_eq!(even.parse(b"4"), Ok((4, "".as_bytes())));
+        assert_eq!(
+            even.parse(b"3"),
+            Err((
+                ParseErr::Expected(vec!["an even number".to_string()]),
+                "3".as_bytes()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_do_binds_patterns_and_lets() {
+        struct Pair {
+            first: i32,
+            second: i32,
+        }
+
+        let pair = map(num, |first| (first, first + 1));
+        let parser = parse_do! {
+            (a, b) <- pair,
+            let sum = a + b;
+            Pair { first, second } <- num.map(|n| Pair { first: n, second: -n }),
+            blank,
+
+            return (sum * first, second),
+        };
+
+        assert_eq!(parser.parse(b"1 2 3"), Ok(((6, -2), "3".as_bytes())));
+    }
+
+    #[test]
+    fn parse_do_guards_and_yields() {
+        let parser = parse_do! {
+            n <- num,
+            guard n > 0 && n < 10 else ParseErr::InvalidMaxval(n);
+
+            yield count(n as usize, num),
+        };
+
+        assert_eq!(parser.parse(b"2 7 8 9"), Ok((vec![7, 8], "9".as_bytes())));
+        assert_eq!(
+            parser.parse(b"12 7"),
+            Err((ParseErr::InvalidMaxval(12), "7".as_bytes()))
+        );
+    }
+
+    #[test]
+    fn context_stacks_labels() {
+        let header = context("header", blank.then(context("width", num)));
+        let (err, rest) = header.parse(b" x").unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "while parsing header > width: invalid number"
+        );
+        assert!(matches!(err.root(), ParseErr::InvalidNum(_)));
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "invalid digit found in string"
+        );
+        assert_eq!(rest, b"x");
+
+        assert_eq!(header.parse(b" 3"), Ok((3, "".as_bytes())));
+    }
+
+    #[test]
+    fn traced_changes_nothing_but_stderr() {
+        let pair = |i| and_then(num, |n| map(num, move |n1| (n, n1))).parse(i);
+        let traced_pair = traced(
+            "pair",
+            traced("first", num).and_then(|n| num.map(move |n1| (n, n1))),
+        );
+
+        for input in [&b"1 2 3"[..], b"1 x", b""] {
+            assert_eq!(traced_pair.parse(input), pair(input));
+        }
+    }
+}
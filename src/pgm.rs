@@ -0,0 +1,2566 @@
+//! Parsers and writers for the netpbm family of images, PGM first and foremost.
+
+use crate::parse_do;
+use crate::parser::*;
+use bstr::ByteSlice;
+use newtype::NewType;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/*******************/
+/* Image Datatypes */
+/*******************/
+
+/// Any image of the netpbm family.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Image<'a> {
+    Pbm(PBM<'a>),
+    Pgm(PGM<'a>),
+    Ppm(PPM<'a>),
+    Pam(PAM<'a>),
+}
+
+impl Image<'_> {
+    /// Copies any contents borrowed from the parsed input, see `Contents::into_owned`.
+    pub fn into_owned(self) -> Image<'static> {
+        match self {
+            Image::Pbm(pbm) => Image::Pbm(pbm.into_owned()),
+            Image::Pgm(pgm) => Image::Pgm(pgm.into_owned()),
+            Image::Ppm(ppm) => Image::Ppm(ppm.into_owned()),
+            Image::Pam(pam) => Image::Pam(pam.into_owned()),
+        }
+    }
+
+    pub fn kind(&self) -> ImageKind {
+        match self {
+            Image::Pbm(_) => ImageKind::Pbm,
+            Image::Pgm(_) => ImageKind::Pgm,
+            Image::Ppm(_) => ImageKind::Ppm,
+            Image::Pam(_) => ImageKind::Pam,
+        }
+    }
+}
+
+/// Bitmap: black and white pixels.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PBM<'a> {
+    pub width: usize,
+    pub height: usize,
+    /// Rows as laid out in a raw PBM: 8 pixels per byte, most significant bit first, 1 for black,
+    /// each row padded to a whole byte.
+    pub contents: Contents<'a>,
+}
+
+impl PBM<'_> {
+    pub fn into_owned(self) -> PBM<'static> {
+        PBM {
+            contents: self.contents.into_owned(),
+            ..self
+        }
+    }
+
+    /// Number of bytes each row occupies in `contents`.
+    pub fn row_width(&self) -> usize {
+        row_width(self.width)
+    }
+
+    /// Whether the pixel at column `x` and row `y` is black, if inside the image.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<bool> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        let byte = self.contents[y * self.row_width() + x / 8];
+        Some(byte & (0x80 >> (x % 8)) != 0)
+    }
+}
+
+/// Bytes per row of a raw bitmap `width` pixels wide.
+fn row_width(width: usize) -> usize {
+    width.div_ceil(8)
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PGM<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub max_grey_val: u16,
+    pub contents: Contents<'a>,
+}
+
+impl PGM<'_> {
+    pub fn into_owned(self) -> PGM<'static> {
+        PGM {
+            contents: self.contents.into_owned(),
+            ..self
+        }
+    }
+
+    /// Number of bytes each sample occupies in `contents`.
+    pub fn sample_width(&self) -> usize {
+        sample_width(self.max_grey_val)
+    }
+
+    /// Every sample of the image, in row-major order, decoded at full depth.
+    pub fn samples(&self) -> impl Iterator<Item = u16> + '_ {
+        decode_samples(&self.contents, self.sample_width())
+    }
+
+    /// Sample at column `x` and row `y`, if inside the image.
+    pub fn sample(&self, x: usize, y: usize) -> Option<u16> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        let sample_width = self.sample_width();
+        let start = (y * self.width + x) * sample_width;
+        decode_samples(&self.contents[start..start + sample_width], sample_width).next()
+    }
+}
+
+/// Pixmap: pixels made of a red, a green and a blue sample.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PPM<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub max_color_val: u16,
+    pub contents: Contents<'a>,
+}
+
+impl PPM<'_> {
+    pub fn into_owned(self) -> PPM<'static> {
+        PPM {
+            contents: self.contents.into_owned(),
+            ..self
+        }
+    }
+
+    /// Number of bytes each sample (not pixel) occupies in `contents`.
+    pub fn sample_width(&self) -> usize {
+        sample_width(self.max_color_val)
+    }
+
+    /// Every pixel of the image as `[red, green, blue]`, in row-major order.
+    pub fn pixels(&self) -> impl Iterator<Item = [u16; 3]> + '_ {
+        let mut samples = decode_samples(&self.contents, self.sample_width());
+
+        std::iter::from_fn(move || Some([samples.next()?, samples.next()?, samples.next()?]))
+    }
+
+    /// Pixel at column `x` and row `y`, if inside the image.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<[u16; 3]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        self.pixels().nth(y * self.width + x)
+    }
+}
+
+/// Arbitrary map: tuples of `tuple_type.depth()` samples each.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PAM<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub max_val: u16,
+    pub tuple_type: TupleType,
+    pub contents: Contents<'a>,
+}
+
+impl PAM<'_> {
+    pub fn into_owned(self) -> PAM<'static> {
+        PAM {
+            contents: self.contents.into_owned(),
+            ..self
+        }
+    }
+
+    /// Number of bytes each sample (not tuple) occupies in `contents`.
+    pub fn sample_width(&self) -> usize {
+        sample_width(self.max_val)
+    }
+
+    /// Every tuple of the image, in row-major order.
+    pub fn tuples(&self) -> impl Iterator<Item = Vec<u16>> + '_ {
+        let sample_width = self.sample_width();
+
+        self.contents
+            .chunks(self.tuple_type.depth() * sample_width)
+            .map(move |tuple| decode_samples(tuple, sample_width).collect())
+    }
+
+    /// Tuple at column `x` and row `y`, if inside the image.
+    pub fn tuple(&self, x: usize, y: usize) -> Option<Vec<u16>> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        self.tuples().nth(y * self.width + x)
+    }
+}
+
+/// Meaning of the samples of a PAM tuple, as named by its `TUPLTYPE` header line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TupleType {
+    Grayscale,
+    GrayscaleAlpha,
+    Rgb,
+    RgbAlpha,
+}
+
+impl TupleType {
+    pub const ALL: [TupleType; 4] = [
+        TupleType::Grayscale,
+        TupleType::GrayscaleAlpha,
+        TupleType::Rgb,
+        TupleType::RgbAlpha,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TupleType::Grayscale => "GRAYSCALE",
+            TupleType::GrayscaleAlpha => "GRAYSCALE_ALPHA",
+            TupleType::Rgb => "RGB",
+            TupleType::RgbAlpha => "RGB_ALPHA",
+        }
+    }
+
+    /// Number of samples in each tuple.
+    pub fn depth(self) -> usize {
+        match self {
+            TupleType::Grayscale => 1,
+            TupleType::GrayscaleAlpha => 2,
+            TupleType::Rgb => 3,
+            TupleType::RgbAlpha => 4,
+        }
+    }
+}
+
+/// Raster as laid out in a raw PGM, PPM or PAM: one byte per sample when the maxval fits in a
+/// byte, two big-endian bytes otherwise. Bitmaps have their own layout, see `PBM`.
+///
+/// Raw rasters are borrowed straight from the parsed input; plain ones have to be decoded into
+/// an owned buffer.
+#[derive(NewType, Clone, PartialEq)]
+pub struct Contents<'a>(Cow<'a, [u8]>);
+
+impl Contents<'_> {
+    /// Copies borrowed contents, so they no longer depend on the parsed input.
+    pub fn into_owned(self) -> Contents<'static> {
+        Contents(Cow::Owned(self.0.into_owned()))
+    }
+}
+
+impl<'a> From<&'a [u8]> for Contents<'a> {
+    fn from(contents: &'a [u8]) -> Self {
+        Contents(Cow::Borrowed(contents))
+    }
+}
+
+impl From<Vec<u8>> for Contents<'_> {
+    fn from(contents: Vec<u8>) -> Self {
+        Contents(Cow::Owned(contents))
+    }
+}
+
+/// Bytes per sample for images with the given maxval.
+fn sample_width(max_val: u16) -> usize {
+    if max_val > u8::MAX.into() {
+        2
+    } else {
+        1
+    }
+}
+
+/// Samples of a raster with the given sample width, decoded at full depth.
+fn decode_samples(raster: &[u8], sample_width: usize) -> impl Iterator<Item = u16> + '_ {
+    raster
+        .chunks(sample_width)
+        .map(|sample| sample.iter().fold(0, |acc, &b| acc << 8 | u16::from(b)))
+}
+
+/// Lays out `samples` as a raster with the given sample width.
+fn encode_samples(samples: impl Iterator<Item = u16>, sample_width: usize) -> Vec<u8> {
+    samples
+        .flat_map(|sample| IntoIterator::into_iter(sample.to_be_bytes()).skip(2 - sample_width))
+        .collect()
+}
+
+impl fmt::Debug for Contents<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = &self.0[..self.0.len().min(20)];
+        write!(f, "{:?}", shown.as_bstr())?;
+        if shown.len() < self.0.len() {
+            write!(f, "...<contents>")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which member of the netpbm family a file holds, as announced by the magic number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageKind {
+    Pbm,
+    Pgm,
+    Ppm,
+    Pam,
+}
+
+impl ImageKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageKind::Pbm => "PBM",
+            ImageKind::Pgm => "PGM",
+            ImageKind::Ppm => "PPM",
+            ImageKind::Pam => "PAM",
+        }
+    }
+}
+
+/// Encoding of the raster, as announced by the magic number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// "P1", "P2" and "P3": samples are whitespace-separated decimal numbers.
+    Plain,
+    /// "P4", "P5", "P6" and "P7": samples are raw bytes.
+    Raw,
+}
+
+/*******************/
+/* Parser builders */
+/*******************/
+
+/// Parses any image of the netpbm family, as told by its magic number.
+pub fn parse_netpbm<'a>(input: ParseInput<'a>) -> ParseResult<'a, Image<'a>> {
+    parse_netpbm_with(input, &ParseLimits::default())
+}
+
+/// Like `parse_netpbm`, checking the image against `limits` instead of the default ones.
+pub fn parse_netpbm_with<'a>(
+    input: ParseInput<'a>,
+    limits: &ParseLimits,
+) -> ParseResult<'a, Image<'a>> {
+    let limits = *limits;
+    let parser = match_magic.and_then(|(kind, encoding)| {
+        move |i| match kind {
+            ImageKind::Pbm => pbm_body(encoding, limits).map(Image::Pbm).parse(i),
+            ImageKind::Pgm => pgm_body(encoding, limits).map(Image::Pgm).parse(i),
+            ImageKind::Ppm => ppm_body(encoding, limits).map(Image::Ppm).parse(i),
+            ImageKind::Pam => pam_body(limits).map(Image::Pam).parse(i),
+        }
+    });
+
+    parser.parse(input)
+}
+
+pub fn parse_pgm<'a>(input: ParseInput<'a>) -> ParseResult<'a, PGM<'a>> {
+    let parser =
+        match_header_version.and_then(|encoding| pgm_body(encoding, ParseLimits::default()));
+    traced("PGM", parser).parse(input)
+}
+
+/// How forgiving `parse_pgm_with` is of damaged files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    /// Pads a truncated raster with zero samples instead of failing, as many viewers do.
+    pub pad_truncated: bool,
+    pub strictness: Strictness,
+    pub limits: ParseLimits,
+}
+
+/// How big an image may be, checked before reading its raster. Headers come from whoever wrote
+/// the file, so this is what keeps a hostile one from asking for all the memory there is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseLimits {
+    pub max_width: usize,
+    pub max_height: usize,
+    pub max_pixels: usize,
+    /// Bytes the raster may take up once decoded.
+    pub max_alloc_bytes: usize,
+    /// Bytes the header may take up, comments included. Only checked when decoding off a
+    /// reader, where the header can't be seen in full before it's been read.
+    pub max_header_bytes: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_width: 1 << 20,
+            max_height: 1 << 20,
+            max_pixels: 1 << 28,
+            max_alloc_bytes: 1 << 30,
+            max_header_bytes: 1 << 16,
+        }
+    }
+}
+
+impl ParseLimits {
+    /// Checks an image of `width` by `height` pixels taking `pixel_bytes` bytes each, returning
+    /// how many pixels it has.
+    pub fn check(
+        &self,
+        width: usize,
+        height: usize,
+        pixel_bytes: usize,
+    ) -> Result<usize, ParseErr> {
+        let exceeded = |limit, max| ParseErr::LimitExceeded { limit, max };
+
+        if width > self.max_width {
+            return Err(exceeded("width", self.max_width));
+        }
+        if height > self.max_height {
+            return Err(exceeded("height", self.max_height));
+        }
+
+        let pixels = width
+            .checked_mul(height)
+            .filter(|&pixels| pixels <= self.max_pixels)
+            .ok_or_else(|| exceeded("pixel count", self.max_pixels))?;
+        pixels
+            .checked_mul(pixel_bytes)
+            .filter(|&bytes| bytes <= self.max_alloc_bytes)
+            .ok_or_else(|| exceeded("raster size", self.max_alloc_bytes))?;
+
+        Ok(pixels)
+    }
+}
+
+/// What `parse_pgm_with` does about samples above the maxval. Headers breaking the format are
+/// rejected either way, since there is no telling what they meant.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Strictness {
+    /// Fails with `SampleExceedsMaxval`.
+    #[default]
+    Strict,
+    /// Clamps them to the maxval, with a warning about the first one.
+    Lenient,
+}
+
+/// Like `parse_pgm`, but going by `options`. Whatever they let through is returned as warnings,
+/// reporting what would have failed otherwise.
+pub fn parse_pgm_with<'a>(
+    input: ParseInput<'a>,
+    options: &ParseOptions,
+) -> ParseResult<'a, (PGM<'a>, Vec<ParseError>)> {
+    let options = *options;
+    let parser = get_pgm_header.and_then(|header| traced("raster", pgm_raster(header, options)));
+    let ((pgm, warnings), rest) = traced("PGM", parser).parse(input)?;
+
+    let warnings = warnings
+        .into_iter()
+        .map(|(err, at)| ParseError {
+            err,
+            position: Position::locate(input, at),
+        })
+        .collect();
+    Ok(((pgm, warnings), rest))
+}
+
+pub fn parse_pbm<'a>(input: ParseInput<'a>) -> ParseResult<'a, PBM<'a>> {
+    match_kind(ImageKind::Pbm)
+        .and_then(|encoding| pbm_body(encoding, ParseLimits::default()))
+        .parse(input)
+}
+
+pub fn parse_ppm<'a>(input: ParseInput<'a>) -> ParseResult<'a, PPM<'a>> {
+    match_kind(ImageKind::Ppm)
+        .and_then(|encoding| ppm_body(encoding, ParseLimits::default()))
+        .parse(input)
+}
+
+pub fn parse_pam<'a>(input: ParseInput<'a>) -> ParseResult<'a, PAM<'a>> {
+    match_kind(ImageKind::Pam)
+        .then(pam_body(ParseLimits::default()))
+        .parse(input)
+}
+
+/// Everything following the magic number of a PGM file.
+fn pgm_body<'a>(encoding: Encoding, limits: ParseLimits) -> impl Parser<'a, PGM<'a>> {
+    let options = ParseOptions {
+        limits,
+        ..ParseOptions::default()
+    };
+
+    pgm_header_fields(encoding)
+        .and_then(move |header| traced("raster", pgm_raster(header, options)).map(|(pgm, _)| pgm))
+}
+
+/// Header fields of a PGM file, up to the single whitespace preceding the raster.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PgmHeader {
+    pub encoding: Encoding,
+    pub width: usize,
+    pub height: usize,
+    pub max_grey_val: u16,
+    /// Length of the header, which is where the raster starts.
+    pub raster_offset: usize,
+}
+
+fn get_pgm_header<'a>(input: ParseInput<'a>) -> ParseResult<'a, PgmHeader> {
+    let (header, rest) =
+        traced("header", match_header_version.and_then(pgm_header_fields)).parse(input)?;
+    let raster_offset = input.len() - rest.len();
+
+    Ok((
+        PgmHeader {
+            raster_offset,
+            ..header
+        },
+        rest,
+    ))
+}
+
+/// Reads the header of a PGM file, leaving the raster alone.
+pub fn probe_pgm(input: ParseInput) -> Result<PgmHeader, ParseError> {
+    parse(get_pgm_header, input).map(|(header, _)| header)
+}
+
+/// Like `probe_pgm`, reading the header off `reader` and leaving it at the start of the raster.
+/// The raster offset counts from where `reader` was.
+pub fn read_pgm_header<R: Read + io::Seek>(reader: &mut R) -> Result<PgmHeader, DecodeErr> {
+    let start = reader.stream_position()?;
+    // Buffering reads past the header, hence seeking back to the raster afterwards.
+    let header = read_pgm_header_buffered(&mut io::BufReader::new(&mut *reader))?;
+    reader.seek(io::SeekFrom::Start(start + header.raster_offset as u64))?;
+
+    Ok(header)
+}
+
+/// Like `read_pgm_header`, for readers that can't seek, such as stdin. The header is read a byte
+/// at a time instead, leaving `reader` right at the start of the raster. Unlike `PgmDecoder`,
+/// this doesn't hold the image against any limits but the length of the header.
+pub fn read_pgm_header_buffered<R: io::BufRead>(reader: &mut R) -> Result<PgmHeader, DecodeErr> {
+    let max_bytes = ParseLimits::default().max_header_bytes;
+    decode_pgm_header(reader, max_bytes).map(|(header, _)| header)
+}
+
+/// The header fields following the magic number of a PGM file.
+fn pgm_header_fields<'a>(encoding: Encoding) -> impl Parser<'a, PgmHeader> {
+    let parser = parse_do! {
+        (width, height) <- traced("dimensions", get_dimensions),
+        max_grey_val <- traced("maxval", get_max_val),
+
+        return PgmHeader {
+            encoding,
+            width,
+            height,
+            max_grey_val,
+            // Only known to `get_pgm_header`, which sees the magic number as well.
+            raster_offset: 0,
+        },
+    };
+
+    context("PGM header", parser)
+}
+
+/// The raster of a PGM file described by `header`, along with the problems `options` let
+/// through.
+fn pgm_raster<'a>(
+    header: PgmHeader,
+    options: ParseOptions,
+) -> impl Parser<'a, (PGM<'a>, Vec<(ParseErr, ParseInput<'a>)>)> {
+    move |input: ParseInput<'a>| {
+        let sample_width = sample_width(header.max_grey_val);
+        // Plain samples are read at full width, lest those above the maxval get cut short.
+        let read_width = match header.encoding {
+            Encoding::Plain => 2,
+            Encoding::Raw => sample_width,
+        };
+        let amount = options
+            .limits
+            .check(header.width, header.height, read_width)
+            .map_err(|err| (err, input))?;
+        let mut warnings = vec![];
+
+        let (raster, rest) = match get_raster(input, header.encoding, amount, read_width) {
+            Err((err @ ParseErr::UnexpectedEof { available, .. }, at)) if options.pad_truncated => {
+                // Keep what is there, short of a raw sample cut in half.
+                let present = match header.encoding {
+                    Encoding::Plain => available,
+                    Encoding::Raw => available / read_width,
+                };
+                let (raster, _) = get_raster(input, header.encoding, present, read_width)?;
+                let mut padded = raster.0.into_owned();
+                padded.resize(amount * read_width, 0);
+
+                warnings.push((err, at));
+                (padded.into(), &input[input.len()..])
+            }
+            result => result?,
+        };
+
+        let max = header.max_grey_val;
+        let excess = find_excess(&raster, read_width, max);
+
+        if let Some((index, value)) = excess {
+            let (x, y) = (index % header.width, index / header.width);
+            let err = ParseErr::SampleExceedsMaxval { x, y, value };
+            let at = locate_sample(input, header.encoding, index, read_width);
+
+            match options.strictness {
+                Strictness::Strict => return Err((err, at)),
+                Strictness::Lenient => warnings.push((err, at)),
+            }
+        }
+
+        let contents = if excess.is_none() && read_width == sample_width {
+            raster
+        } else {
+            let samples = decode_samples(&raster, read_width).map(|value| value.min(max));
+            encode_samples(samples, sample_width).into()
+        };
+
+        let pgm = PGM {
+            width: header.width,
+            height: header.height,
+            max_grey_val: header.max_grey_val,
+            contents,
+        };
+        Ok(((pgm, warnings), rest))
+    }
+}
+
+/// The first sample of `raster` above `max`, along with its index. Samples can't go above what
+/// `sample_width` bytes hold, so rasters whose maxval is just that aren't looked through.
+fn find_excess(raster: &[u8], sample_width: usize, max: u16) -> Option<(usize, u16)> {
+    match sample_width {
+        1 if max >= 255 => None,
+        1 => raster
+            .iter()
+            .position(|&b| u16::from(b) > max)
+            .map(|index| (index, u16::from(raster[index]))),
+        _ if max == u16::MAX => None,
+        _ => decode_samples(raster, sample_width)
+            .enumerate()
+            .find(|&(_, value)| value > max),
+    }
+}
+
+/// Everything following the magic number of a PBM file.
+fn pbm_body<'a>(encoding: Encoding, limits: ParseLimits) -> impl Parser<'a, PBM<'a>> {
+    parse_do! {
+        (width, height) <- get_dimensions,
+        let raster = move |i: ParseInput<'a>| {
+            // Packed rows never take up more than a byte per pixel.
+            limits.check(width, height, 1).map_err(|err| (err, i))?;
+
+            match encoding {
+                Encoding::Plain => get_bits(i, width, height)
+                    .map(|(bits, rest)| (Contents::from(bits), rest)),
+                Encoding::Raw => get_bytes(i, row_width(width) * height)
+                    .map(|(bytes, rest)| (Contents::from(bytes), rest)),
+            }
+        };
+
+        // Mapped over rather than returned, so that the contents are moved instead of copied.
+        yield raster.map(move |contents| PBM {
+            width,
+            height,
+            contents,
+        }),
+    }
+}
+
+/// Everything following the magic number of a PPM file.
+fn ppm_body<'a>(encoding: Encoding, limits: ParseLimits) -> impl Parser<'a, PPM<'a>> {
+    parse_do! {
+        (width, height) <- get_dimensions,
+        max_color_val <- get_max_val,
+        let sample_width = sample_width(max_color_val);
+        let raster = move |i: ParseInput<'a>| {
+            let pixels = limits
+                .check(width, height, 3 * sample_width)
+                .map_err(|err| (err, i))?;
+            get_raster(i, encoding, 3 * pixels, sample_width)
+        };
+
+        yield raster.map(move |contents| PPM {
+            width,
+            height,
+            max_color_val,
+            contents,
+        }),
+    }
+}
+
+/// Everything following the magic number of a PAM file, which is always raw.
+fn pam_body<'a>(limits: ParseLimits) -> impl Parser<'a, PAM<'a>> {
+    parse_do! {
+        PamHeader { width, height, max_val, tuple_type } <- get_pam_header,
+        let tuple_bytes = tuple_type.depth() * sample_width(max_val);
+        let raster = move |i: ParseInput<'a>| {
+            let pixels = limits
+                .check(width, height, tuple_bytes)
+                .map_err(|err| (err, i))?;
+            get_bytes(i, pixels * tuple_bytes)
+        };
+
+        yield raster.map(move |bytes| PAM {
+            width,
+            height,
+            max_val,
+            tuple_type,
+            contents: bytes.into(),
+        }),
+    }
+}
+
+/// Header fields of a PAM file, as given by its keyword lines.
+#[derive(Debug, Clone, Copy)]
+struct PamHeader {
+    width: usize,
+    height: usize,
+    max_val: u16,
+    tuple_type: TupleType,
+}
+
+/// One keyword line of a PAM header.
+#[derive(Debug)]
+enum PamLine {
+    Width(i32),
+    Height(i32),
+    Depth(i32),
+    MaxVal(i32),
+    TupleType(TupleType),
+    EndHdr,
+}
+
+fn get_pam_line(input: ParseInput) -> ParseResult<PamLine> {
+    let ((), input) = skip_filler(input)?;
+    let parser = get_field.and_then(|keyword: &[u8]| {
+        move |i| match keyword {
+            b"WIDTH" => get_num.map(PamLine::Width).parse(i),
+            b"HEIGHT" => get_num.map(PamLine::Height).parse(i),
+            b"DEPTH" => get_num.map(PamLine::Depth).parse(i),
+            b"MAXVAL" => get_num.map(PamLine::MaxVal).parse(i),
+            b"TUPLTYPE" => get_tuple_type.map(PamLine::TupleType).parse(i),
+            b"ENDHDR" => Ok((PamLine::EndHdr, i)),
+            _ => Err((
+                ParseErr::UnknownPamKeyword(keyword.to_str_lossy().into()),
+                input,
+            )),
+        }
+    });
+
+    parser.parse(input)
+}
+
+/// Reads keyword lines up to `ENDHDR`, checking that every field is there and consistent.
+fn get_pam_header(input: ParseInput) -> ParseResult<PamHeader> {
+    let (mut width, mut height, mut depth, mut max_val, mut tuple_type) =
+        (None, None, None, None, None);
+    let mut rest = input;
+
+    loop {
+        let (line, new_rest) = get_pam_line(rest)?;
+        rest = new_rest;
+
+        match line {
+            PamLine::Width(n) => width = Some(n),
+            PamLine::Height(n) => height = Some(n),
+            PamLine::Depth(n) => depth = Some(n),
+            PamLine::MaxVal(n) => max_val = Some(n),
+            PamLine::TupleType(t) => tuple_type = Some(t),
+            PamLine::EndHdr => break,
+        }
+    }
+
+    let missing = |field| (ParseErr::MissingPamField(field), input);
+    let (width, height) = check_dimensions(
+        width.ok_or_else(|| missing("WIDTH"))?,
+        height.ok_or_else(|| missing("HEIGHT"))?,
+    )
+    .map_err(|err| (err, input))?;
+    let max_val = max_val.ok_or_else(|| missing("MAXVAL"))?;
+
+    let header = PamHeader {
+        width,
+        height,
+        max_val: check_max_val(max_val).map_err(|err| (err, input))?,
+        tuple_type: tuple_type.ok_or_else(|| missing("TUPLTYPE"))?,
+    };
+    let depth = depth.ok_or_else(|| missing("DEPTH"))?;
+    let depth = usize::try_from(depth).map_err(|_| (ParseErr::InvalidDepth(depth), input))?;
+
+    if depth != header.tuple_type.depth() {
+        return Err((
+            ParseErr::TupleDepthMismatch(header.tuple_type.name(), depth),
+            input,
+        ));
+    }
+
+    Ok((header, rest))
+}
+
+/// Reads the value of a `TUPLTYPE` line, which runs until the end of the line.
+fn get_tuple_type(input: ParseInput) -> ParseResult<TupleType> {
+    let line = parse_do! {
+        line <- take_while(|b| b != b'\n'),
+        _ <- or(byte(b'\n').map(|_| ()), eof),
+
+        return line,
+    };
+    let (line, rest) = line.parse(input)?;
+    let name = line.trim();
+
+    let tuple_type = TupleType::ALL
+        .iter()
+        .find(|tuple_type| tuple_type.name().as_bytes() == name)
+        .ok_or_else(|| {
+            (
+                ParseErr::UnknownTupleType(name.to_str_lossy().into()),
+                input,
+            )
+        })?;
+
+    Ok((*tuple_type, rest))
+}
+
+const MAGICS: [(&str, ImageKind, Encoding); 7] = [
+    ("P1", ImageKind::Pbm, Encoding::Plain),
+    ("P2", ImageKind::Pgm, Encoding::Plain),
+    ("P3", ImageKind::Ppm, Encoding::Plain),
+    ("P4", ImageKind::Pbm, Encoding::Raw),
+    ("P5", ImageKind::Pgm, Encoding::Raw),
+    ("P6", ImageKind::Ppm, Encoding::Raw),
+    ("P7", ImageKind::Pam, Encoding::Raw),
+];
+
+/// Magic number of PGM files with the given encoding.
+pub fn pgm_magic(encoding: Encoding) -> &'static str {
+    match encoding {
+        Encoding::Plain => "P2",
+        Encoding::Raw => "P5",
+    }
+}
+
+/// Which kind of image `input` starts with, going by the magic number alone.
+pub fn probe_kind(input: ParseInput) -> Option<ImageKind> {
+    MAGICS
+        .iter()
+        .find(|(magic, _, _)| input.starts_with(magic.as_bytes()))
+        .map(|&(_, kind, _)| kind)
+}
+
+/// Reads the magic number, telling which kind of image follows and how it's encoded.
+fn match_magic(input: ParseInput) -> ParseResult<(ImageKind, Encoding)> {
+    match_magic_if(|_| true).parse(input)
+}
+
+/// Reads the magic number of an image of the given kind, telling how it's encoded.
+fn match_kind<'a>(kind: ImageKind) -> impl Parser<'a, Encoding> {
+    match_magic_if(move |found| found == kind).map(|(_, encoding)| encoding)
+}
+
+/// Reads any of the magic numbers announcing a kind of image `wanted` accepts.
+fn match_magic_if<'a, F>(wanted: F) -> impl Parser<'a, (ImageKind, Encoding)>
+where
+    F: Fn(ImageKind) -> bool,
+{
+    let magics = MAGICS
+        .iter()
+        .filter(|(_, kind, _)| wanted(*kind))
+        .map(|&(magic, kind, encoding)| tag(magic).map(move |_| (kind, encoding)))
+        .collect();
+
+    parse_do! {
+        found <- choice(magics),
+        _ <- whitespace1,
+        skip_filler,
+
+        return found,
+    }
+}
+
+fn match_header_version(input: ParseInput) -> ParseResult<Encoding> {
+    traced("magic", match_kind(ImageKind::Pgm)).parse(input)
+}
+
+/// Skips whitespace and `#` comments, which the netpbm header allows between any two fields.
+fn skip_filler(input: ParseInput) -> ParseResult<()> {
+    many(or(whitespace1, comment)).map(|_| ()).parse(input)
+}
+
+/// A comment, up to and including the newline that ends it.
+fn comment<'a>(input: ParseInput<'a>) -> ParseResult<'a, &'a [u8]> {
+    let parser = parse_do! {
+        _ <- byte(b'#'),
+        text <- take_while(|b| b != b'\n'),
+        _ <- or(byte(b'\n').map(|_| ()), eof),
+
+        return text,
+    };
+
+    parser.parse(input)
+}
+
+/// Reads the next whitespace-delimited header field.
+fn get_field<'a>(input: ParseInput<'a>) -> ParseResult<'a, &'a [u8]> {
+    let parser = parse_do! {
+        skip_filler,
+        field <- take_while1("a field", |b| !b.is_ascii_whitespace() && b != b'#'),
+        // Skip the single whitespace ending the field (there should be always one in PGM
+        // specification). A comment right after the field is ended by a newline, which then
+        // plays that part.
+        _ <- or(or(whitespace.map(|_| ()), comment.map(|_| ())), eof),
+
+        return field,
+    };
+
+    parser.parse(input)
+}
+
+/// Reads the width and height of an image.
+fn get_dimensions(input: ParseInput) -> ParseResult<(usize, usize)> {
+    let ((), input) = skip_filler(input)?;
+    let ((width, height), rest) = context("width", get_num)
+        .and_then(|w| context("height", get_num).map(move |h| (w, h)))
+        .parse(input)?;
+    let dimensions = check_dimensions(width, height).map_err(|err| (err, input))?;
+
+    Ok((dimensions, rest))
+}
+
+fn check_dimensions(width: i32, height: i32) -> Result<(usize, usize), ParseErr> {
+    if width >= 0 && height >= 0 {
+        Ok((width as usize, height as usize))
+    } else {
+        Err(ParseErr::InvalidDimensions { width, height })
+    }
+}
+
+fn get_max_val(input: ParseInput) -> ParseResult<u16> {
+    let ((), input) = skip_filler(input)?;
+    let (max_val, rest) = context("maxval", get_num).parse(input)?;
+    let max_val = check_max_val(max_val).map_err(|err| (err, input))?;
+
+    Ok((max_val, rest))
+}
+
+fn check_max_val(max_val: i32) -> Result<u16, ParseErr> {
+    u16::try_from(max_val)
+        .ok()
+        .filter(|&max_val| max_val > 0)
+        .ok_or(ParseErr::InvalidMaxval(max_val))
+}
+
+fn get_num(input: ParseInput) -> ParseResult<i32> {
+    // Errors are reported at the number itself, rather than at what precedes it.
+    let ((), input) = skip_filler(input)?;
+    let (raw_num_str, rest) = get_field(input)?;
+
+    let num = raw_num_str.to_str().map_or_else(
+        |er| Err((ParseErr::Utf8Error(er), input)),
+        |s| {
+            s.parse::<i32>()
+                .map_err(|er| (ParseErr::InvalidNum(er), input))
+        },
+    )?;
+
+    Ok((num, rest))
+}
+
+/// Borrows the next `amount` bytes, as found in a raw raster.
+fn get_bytes<'a>(input: ParseInput<'a>, amount: usize) -> ParseResult<'a, &'a [u8]> {
+    take(amount).parse(input)
+}
+
+/// Reads a PGM or PPM raster of `amount` samples, laid out as in a raw file.
+fn get_raster<'a>(
+    input: ParseInput<'a>,
+    encoding: Encoding,
+    amount: usize,
+    sample_width: usize,
+) -> ParseResult<'a, Contents<'a>> {
+    match encoding {
+        Encoding::Plain => {
+            get_samples(input, amount, sample_width).map(|(samples, rest)| (samples.into(), rest))
+        }
+        Encoding::Raw => {
+            get_bytes(input, amount * sample_width).map(|(bytes, rest)| (bytes.into(), rest))
+        }
+    }
+}
+
+/// Reads `amount` whitespace-separated decimal samples, as found in a plain PGM raster, and lays
+/// them out as a raw raster with `sample_width` big-endian bytes each.
+fn get_samples(input: ParseInput, amount: usize, sample_width: usize) -> ParseResult<Vec<u8>> {
+    let (what, max) = match sample_width {
+        1 => ("a sample of at most 255", u8::MAX.into()),
+        _ => ("a sample of at most 65535", u16::MAX.into()),
+    };
+    let sample = get_num.verify(what, move |&sample| (0..=max).contains(&sample));
+    // Every sample takes up a byte at least, whatever the header claims.
+    let capacity = amount.min(input.len()) * sample_width;
+    let parser = fold_samples(
+        amount,
+        sample,
+        || Vec::with_capacity(capacity),
+        |mut raster, _, sample| {
+            raster.extend_from_slice(&(sample as u16).to_be_bytes()[2 - sample_width..]);
+            raster
+        },
+    );
+
+    parser.parse(input)
+}
+
+/// Where sample `index` of a raster read by `get_raster` starts.
+fn locate_sample<'a>(
+    input: ParseInput<'a>,
+    encoding: Encoding,
+    index: usize,
+    sample_width: usize,
+) -> ParseInput<'a> {
+    let before = get_raster(input, encoding, index, sample_width);
+    let rest = before.map_or(input, |(_, rest)| rest);
+
+    skip_filler(rest).map_or(rest, |((), sample)| sample)
+}
+
+/// Reads the `0` and `1` pixels of a plain PBM raster, where whitespace is optional, and packs
+/// them into rows as laid out in a raw PBM.
+fn get_bits(input: ParseInput, width: usize, height: usize) -> ParseResult<Vec<u8>> {
+    let fold = move |mut packed: Vec<u8>, i: usize, black: bool| {
+        let (y, x) = (i / width, i % width);
+        let byte = y * row_width(width) + x / 8;
+        // Grown as pixels show up, rather than trusting the header with the whole size.
+        if packed.len() <= byte {
+            packed.resize(byte + 1, 0);
+        }
+        if black {
+            packed[byte] |= 0x80 >> (x % 8);
+        }
+        packed
+    };
+    let parser = fold_samples(width * height, get_bit, Vec::new, fold).map(move |mut packed| {
+        packed.resize(row_width(width) * height, 0);
+        packed
+    });
+
+    parser.parse(input)
+}
+
+/// Like `fold_many`, for the `needed` samples of a plain raster: each is combined into the
+/// output with `fun` as soon as it's read, along with its index, so none are held on to in
+/// between. Running out of input partway is reported as `UnexpectedEof`, along with how many
+/// samples were there.
+fn fold_samples<'a, Out, Acc, P, I, F>(
+    needed: usize,
+    sample: P,
+    init: I,
+    fun: F,
+) -> impl Parser<'a, Acc>
+where
+    P: Parser<'a, Out>,
+    I: Fn() -> Acc,
+    F: Fn(Acc, usize, Out) -> Acc,
+{
+    move |input: ParseInput<'a>| {
+        let mut acc = init();
+        let mut rest = input;
+
+        for available in 0..needed {
+            match sample.parse(rest) {
+                Ok((out, next)) => {
+                    acc = fun(acc, available, out);
+                    rest = next;
+                }
+                Err((ParseErr::NoValidFieldLeft, end)) => {
+                    return Err((ParseErr::UnexpectedEof { needed, available }, end));
+                }
+                Err(er) => return Err(er),
+            }
+        }
+
+        Ok((acc, rest))
+    }
+}
+
+/// Reads a pixel of a plain PBM raster, telling whether it's black.
+fn get_bit(input: ParseInput) -> ParseResult<bool> {
+    let (_, rest) = take_while(|b| b.is_ascii_whitespace()).parse(input)?;
+
+    match rest.first() {
+        Some(b'0') => Ok((false, &rest[1..])),
+        Some(b'1') => Ok((true, &rest[1..])),
+        Some(&bit) => Err((ParseErr::InvalidBit(bit), rest)),
+        None => Err((ParseErr::NoValidFieldLeft, rest)),
+    }
+}
+
+/***********/
+/* Writers */
+/***********/
+
+/// How `write_pgm` lays out its output.
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    pub encoding: Encoding,
+    /// Maximum length of a raster line in plain output; `None` puts each image row on one line.
+    /// Rows always start on a new line. A single sample longer than the limit still gets written.
+    pub line_width: Option<usize>,
+    /// Written as `#` comments right after the magic number, one per line.
+    pub comments: Vec<String>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            encoding: Encoding::Raw,
+            // Limit recommended by the netpbm specification.
+            line_width: Some(70),
+            comments: vec![],
+        }
+    }
+}
+
+pub fn write_pgm<W: Write>(out: &mut W, pgm: &PGM<'_>, options: &WriteOptions) -> io::Result<()> {
+    writeln!(out, "{}", pgm_magic(options.encoding))?;
+    for line in options.comments.iter().flat_map(|comment| comment.lines()) {
+        writeln!(out, "# {}", line)?;
+    }
+    writeln!(out, "{} {}", pgm.width, pgm.height)?;
+    writeln!(out, "{}", pgm.max_grey_val)?;
+
+    match options.encoding {
+        Encoding::Plain => write_samples(out, pgm, options.line_width),
+        Encoding::Raw => out.write_all(&pgm.contents),
+    }
+}
+
+pub fn write_pam<W: Write>(out: &mut W, pam: &PAM<'_>) -> io::Result<()> {
+    writeln!(out, "P7")?;
+    writeln!(out, "WIDTH {}", pam.width)?;
+    writeln!(out, "HEIGHT {}", pam.height)?;
+    writeln!(out, "DEPTH {}", pam.tuple_type.depth())?;
+    writeln!(out, "MAXVAL {}", pam.max_val)?;
+    writeln!(out, "TUPLTYPE {}", pam.tuple_type.name())?;
+    writeln!(out, "ENDHDR")?;
+
+    out.write_all(&pam.contents)
+}
+
+/// Writes the raster of `pgm` as whitespace-separated decimal samples.
+fn write_samples<W: Write>(
+    out: &mut W,
+    pgm: &PGM<'_>,
+    line_width: Option<usize>,
+) -> io::Result<()> {
+    let mut line_len = 0;
+
+    for (i, sample) in pgm.samples().enumerate() {
+        let sample = sample.to_string();
+
+        let new_row = i % pgm.width == 0;
+        let too_long = line_width.is_some_and(|max| line_len + 1 + sample.len() > max);
+        if line_len > 0 && (new_row || too_long) {
+            writeln!(out)?;
+            line_len = 0;
+        }
+
+        if line_len > 0 {
+            write!(out, " ")?;
+            line_len += 1;
+        }
+        write!(out, "{}", sample)?;
+        line_len += sample.len();
+    }
+
+    if line_len > 0 {
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+/*****************/
+/* Image streams */
+/*****************/
+
+/// Iterator over the images of an input holding several PGM files back to back.
+///
+/// Stops after the first malformed image, since there is no telling where the next one starts.
+pub struct PgmStream<'a> {
+    input: ParseInput<'a>,
+    rest: ParseInput<'a>,
+    index: usize,
+    failed: bool,
+}
+
+/// Failure to parse the image at position `index` (starting from 0) of a `PgmStream`. The error
+/// is located relative to the start of the whole stream.
+#[derive(Debug, PartialEq)]
+pub struct StreamErr {
+    pub index: usize,
+    pub err: ParseError,
+}
+
+impl fmt::Display for StreamErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "image {}: {}", self.index, self.err)
+    }
+}
+
+impl Error for StreamErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.err.source()
+    }
+}
+
+pub fn parse_pgm_stream(input: ParseInput) -> PgmStream {
+    PgmStream {
+        input,
+        rest: input,
+        index: 0,
+        failed: false,
+    }
+}
+
+impl<'a> Iterator for PgmStream<'a> {
+    type Item = Result<PGM<'a>, StreamErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        // Plain rasters are usually followed by a newline before the next image begins.
+        let ((), rest) = skip_filler(self.rest).ok()?;
+
+        if rest.is_empty() {
+            return None;
+        }
+
+        let index = self.index;
+        self.index += 1;
+
+        match parse_pgm(rest) {
+            Ok((pgm, rest)) => {
+                self.rest = rest;
+                Some(Ok(pgm))
+            }
+            Err((err, rest)) => {
+                self.failed = true;
+                let position = Position::locate(self.input, rest);
+                Some(Err(StreamErr {
+                    index,
+                    err: ParseError { err, position },
+                }))
+            }
+        }
+    }
+}
+
+/*****************/
+/* Image decoder */
+/*****************/
+
+/// Decodes a PGM file straight from a reader, one row at a time, so that the whole image never
+/// needs to be held in memory.
+///
+/// Rows are laid out as in a raw raster, whatever the encoding of the file.
+pub struct PgmDecoder<R> {
+    reader: R,
+    header: PgmHeader,
+    rows_left: usize,
+    /// Where the reader is in the file, to locate errors in a plain raster.
+    position: Position,
+}
+
+/// Failure to decode a PGM file from a reader.
+#[derive(Debug)]
+pub enum DecodeErr {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl From<io::Error> for DecodeErr {
+    fn from(er: io::Error) -> Self {
+        DecodeErr::Io(er)
+    }
+}
+
+impl fmt::Display for DecodeErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeErr::Io(er) => write!(f, "{}", er),
+            DecodeErr::Parse(er) => write!(f, "{}", er),
+        }
+    }
+}
+
+impl Error for DecodeErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DecodeErr::Io(er) => er.source(),
+            DecodeErr::Parse(er) => er.source(),
+        }
+    }
+}
+
+impl<R: io::BufRead> PgmDecoder<R> {
+    /// Reads the header off `reader`, leaving it at the start of the raster.
+    pub fn new(reader: R) -> Result<Self, DecodeErr> {
+        PgmDecoder::with_limits(reader, ParseLimits::default())
+    }
+
+    /// Like `new`, checking the image against `limits`. Only a row is held in memory at a time,
+    /// but the whole raster still counts towards them.
+    pub fn with_limits(mut reader: R, limits: ParseLimits) -> Result<Self, DecodeErr> {
+        let (header, position) = decode_pgm_header(&mut reader, limits.max_header_bytes)?;
+        let pixel_bytes = sample_width(header.max_grey_val);
+
+        if let Err(err) = limits.check(header.width, header.height, pixel_bytes) {
+            return Err(DecodeErr::Parse(ParseError { err, position }));
+        }
+
+        Ok(PgmDecoder {
+            reader,
+            header,
+            rows_left: header.height,
+            position,
+        })
+    }
+
+    pub fn header(&self) -> PgmHeader {
+        self.header
+    }
+
+    fn read_row(&mut self) -> Result<Vec<u8>, DecodeErr> {
+        let sample_width = sample_width(self.header.max_grey_val);
+        let mut row = vec![0; self.header.width * sample_width];
+
+        let max = self.header.max_grey_val;
+        let y = self.header.height - self.rows_left;
+
+        match self.header.encoding {
+            Encoding::Raw => {
+                let read = read_up_to(&mut self.reader, &mut row)?;
+                if read < row.len() {
+                    row[..read].iter().for_each(|&b| self.advance(b));
+                    return Err(DecodeErr::Parse(ParseError {
+                        err: ParseErr::UnexpectedEof {
+                            needed: row.len() * self.header.height,
+                            available: row.len() * y + read,
+                        },
+                        position: self.position,
+                    }));
+                }
+
+                for (x, sample) in row.chunks(sample_width).enumerate() {
+                    let value = decode_samples(sample, sample_width).sum();
+                    if value > max {
+                        return Err(DecodeErr::Parse(ParseError {
+                            err: ParseErr::SampleExceedsMaxval { x, y, value },
+                            position: self.position,
+                        }));
+                    }
+                    sample.iter().for_each(|&b| self.advance(b));
+                }
+            }
+            Encoding::Plain => {
+                for (x, sample) in row.chunks_mut(sample_width).enumerate() {
+                    let Some((start, token)) = self.read_token()? else {
+                        let (width, height) = (self.header.width, self.header.height);
+                        return Err(DecodeErr::Parse(ParseError {
+                            err: ParseErr::UnexpectedEof {
+                                needed: width * height,
+                                available: (height - self.rows_left) * width + x,
+                            },
+                            position: self.position,
+                        }));
+                    };
+                    let located = |err| {
+                        DecodeErr::Parse(ParseError {
+                            err,
+                            position: start,
+                        })
+                    };
+
+                    let value = String::from_utf8_lossy(&token)
+                        .parse::<u16>()
+                        .map_err(|er| located(ParseErr::InvalidNum(er)))?;
+                    if value > max {
+                        return Err(located(ParseErr::SampleExceedsMaxval { x, y, value }));
+                    }
+                    sample.copy_from_slice(&value.to_be_bytes()[2 - sample_width..]);
+                }
+            }
+        }
+
+        Ok(row)
+    }
+
+    /// Reads the next whitespace-separated token, along with where it starts, or `None` at the
+    /// end of the input. Comments are skipped, as between the fields of a header.
+    fn read_token(&mut self) -> Result<Option<(Position, Vec<u8>)>, DecodeErr> {
+        let mut token = vec![];
+        let mut start = self.position;
+        let mut in_comment = false;
+
+        while let Some(byte) = read_byte(&mut self.reader)? {
+            let at = self.position;
+            self.advance(byte);
+
+            if in_comment {
+                in_comment = byte != b'\n';
+                if !in_comment && !token.is_empty() {
+                    break;
+                }
+            } else if byte == b'#' {
+                in_comment = true;
+            } else if byte.is_ascii_whitespace() {
+                if !token.is_empty() {
+                    break;
+                }
+            } else if token.len() == MAX_SAMPLE_LEN {
+                // No sample is spelled this long, so there is no point reading on.
+                token.push(byte);
+                let err = match String::from_utf8_lossy(&token).parse::<u16>() {
+                    Err(er) => er,
+                    // Padded with zeros, which is still too long to bother with.
+                    Ok(_) => "65536".parse::<u16>().unwrap_err(),
+                };
+                return Err(DecodeErr::Parse(ParseError {
+                    err: ParseErr::InvalidNum(err),
+                    position: start,
+                }));
+            } else {
+                if token.is_empty() {
+                    start = at;
+                }
+                token.push(byte);
+            }
+        }
+
+        Ok(Some((start, token)).filter(|(_, token)| !token.is_empty()))
+    }
+
+    fn advance(&mut self, byte: u8) {
+        self.position.offset += 1;
+        if byte == b'\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+    }
+}
+
+impl<R: io::BufRead> Iterator for PgmDecoder<R> {
+    type Item = Result<Vec<u8>, DecodeErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rows_left == 0 {
+            return None;
+        }
+
+        let row = self.read_row();
+        // There is no telling where the following rows start after a malformed one.
+        self.rows_left = if row.is_ok() { self.rows_left - 1 } else { 0 };
+        Some(row)
+    }
+}
+
+/// Longest plain sample `PgmDecoder` reads: five digits, and a sign.
+const MAX_SAMPLE_LEN: usize = 6;
+
+/// Reads a PGM header off `reader`, a byte at a time so as not to go past it, along with the
+/// position of the raster. Gives up once the header is longer than `max_bytes`.
+fn decode_pgm_header(
+    reader: &mut impl io::BufRead,
+    max_bytes: usize,
+) -> Result<(PgmHeader, Position), DecodeErr> {
+    let mut header = vec![];
+    // Fields started so far, the magic number included, and what the last byte belonged to.
+    let (mut fields, mut in_field, mut in_comment) = (0, false, false);
+
+    loop {
+        let byte = read_byte(reader)?;
+        // Whether the header is over, and whether to parse what there is of it so far.
+        let (complete, check) = match byte {
+            None => (true, true),
+            Some(_) if header.len() == max_bytes => {
+                return Err(DecodeErr::Parse(ParseError {
+                    err: ParseErr::LimitExceeded {
+                        limit: "header size",
+                        max: max_bytes,
+                    },
+                    position: Position::locate(&header, &[]),
+                }));
+            }
+            Some(b) => {
+                header.push(b);
+
+                if in_comment {
+                    in_comment = b != b'\n';
+                    // A comment right after the maxval ends the header along with itself.
+                    (fields == 4 && !in_comment, false)
+                } else if b.is_ascii_whitespace() || b == b'#' {
+                    in_comment = b == b'#';
+                    let field_end = std::mem::replace(&mut in_field, false);
+                    (field_end && fields == 4 && !in_comment, field_end)
+                } else {
+                    if !in_field {
+                        fields += 1;
+                        in_field = true;
+                    }
+                    // The magic number is worth checking early on, in case this is no PGM
+                    // file at all.
+                    (false, header.len() == 2)
+                }
+            }
+        };
+
+        // Parsing only once a field is over keeps this linear in the length of the header.
+        if !check && !complete {
+            continue;
+        }
+
+        match get_pgm_header(&header) {
+            Ok((pgm_header, _)) if complete => {
+                return Ok((pgm_header, Position::locate(&header, &[])));
+            }
+            // The header goes on past what has been read so far.
+            Ok(_) => continue,
+            Err((err, _)) if !complete && err.root() == &ParseErr::NoValidFieldLeft => continue,
+            Err((err, rest)) => {
+                let position = Position::locate(&header, rest);
+                return Err(DecodeErr::Parse(ParseError { err, position }));
+            }
+        }
+    }
+}
+
+/// Fills as much of `buf` as `reader` has left, telling how much that was.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(er) if er.kind() == io::ErrorKind::Interrupted => {}
+            Err(er) => return Err(er),
+        }
+    }
+
+    Ok(filled)
+}
+
+fn read_byte(reader: &mut impl io::BufRead) -> io::Result<Option<u8>> {
+    let byte = reader.fill_buf()?.first().copied();
+    if byte.is_some() {
+        reader.consume(1);
+    }
+    Ok(byte)
+}
+
+/*********/
+/* Tests */
+/*********/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn match_header_version_obv() {
+        let mock_header: &[u8] = indoc!(
+            "P5
+            120 32"
+        )
+            .as_bytes();
+
+        assert_eq!(
+            match match_header_version(mock_header) {
+                Ok((encoding, s)) => Ok((encoding, s.as_bstr())),
+                Err((er, s)) => Err((er, s.as_bstr())),
+            },
+            Ok((Encoding::Raw, "120 32".as_bytes().as_bstr())),
+        );
+    }
+
+    #[test]
+    fn match_header_version_plain() {
+        assert_eq!(
+            match_header_version(b"P2\n3 1"),
+            Ok((Encoding::Plain, "3 1".as_bytes()))
+        );
+    }
+
+    #[test]
+    fn get_num_single() {
+        assert_eq!(get_num("12".as_bytes()), Ok((12, "".as_bytes())));
+    }
+
+    #[test]
+    fn get_num_multiple() {
+        assert_eq!(get_num("12 24".as_bytes()), Ok((12, "24".as_bytes())));
+    }
+
+    #[test]
+    fn and_then_map_2_nums() {
+        let input = b"12 14 16";
+
+        let res = and_then(get_num, move |n1| map(get_num, move |n2| (n1, n2))).parse(input);
+
+        assert_eq!(res, Ok(((12, 14), "16".as_bytes())),);
+    }
+
+    #[test]
+    fn get_num_leading_whitespace() {
+        assert_eq!(get_num(" \n 12  24".as_bytes()), Ok((12, " 24".as_bytes())));
+    }
+
+    #[test]
+    fn get_num_skips_comments() {
+        assert_eq!(
+            get_num(b"# width\n  # still width\n12# height\n24"),
+            Ok((12, "24".as_bytes()))
+        );
+    }
+
+    #[test]
+    fn parse_pgm_commented_header() {
+        let commented = indoc!(
+            "P2
+            # CREATOR: GIMP PNM Filter Version 1.1
+            2 # width
+            1
+            # maxval follows
+            255# comment ending the header
+            7 9"
+        );
+        let plain = parse_pgm(commented.as_bytes()).map(|(pgm, _)| pgm);
+        let raw = parse_pgm(b"P5\n2 1\n255\n\x07\x09").map(|(pgm, _)| pgm);
+
+        assert_eq!(plain, raw);
+    }
+
+    #[test]
+    fn parse_pgm_plain_and_raw_agree() {
+        let plain = parse_pgm(b"P2\n3 2\n255\n0 1  2\n\n 3 4 255\n").map(|(pgm, _)| pgm);
+        let raw = parse_pgm(b"P5\n3 2\n255\n\x00\x01\x02\x03\x04\xff").map(|(pgm, _)| pgm);
+
+        assert_eq!(plain, raw);
+    }
+
+    #[test]
+    fn parse_pgm_16_bit() {
+        let plain = parse_pgm(b"P2\n2 1\n65535\n258 65535\n").map(|(pgm, _)| pgm);
+        let raw = parse_pgm(b"P5\n2 1\n65535\n\x01\x02\xff\xff").map(|(pgm, _)| pgm);
+
+        assert_eq!(plain, raw);
+
+        let pgm = raw.unwrap();
+        assert_eq!(pgm.max_grey_val, 65535);
+        assert_eq!(pgm.samples().collect::<Vec<_>>(), vec![258, 65535]);
+        assert_eq!(pgm.sample(1, 0), Some(65535));
+        assert_eq!(pgm.sample(2, 0), None);
+    }
+
+    #[test]
+    fn parse_pgm_stream_all_images() {
+        let input = b"P2\n1 1\n255\n7\n\nP5\n2 1\n255\n\x08\x09P5\n1 1\n255\n\x0a";
+
+        let widths: Vec<_> = parse_pgm_stream(input)
+            .map(|pgm| pgm.map(|pgm| pgm.width))
+            .collect();
+
+        assert_eq!(widths, vec![Ok(1), Ok(2), Ok(1)]);
+    }
+
+    #[test]
+    fn parse_pgm_stream_reports_failing_image() {
+        let input = b"P5\n1 1\n255\n\x07P5\nx 1\n255\n\x08P5\n1 1\n255\n\x09";
+
+        let mut stream = parse_pgm_stream(input);
+
+        assert!(stream.next().unwrap().is_ok());
+        assert!(matches!(
+            stream.next(),
+            Some(Err(StreamErr {
+                index: 1,
+                err: ParseError {
+                    err,
+                    position: Position {
+                        offset: 15,
+                        line: 5,
+                        column: 1,
+                    },
+                },
+            })) if matches!(err.root(), ParseErr::InvalidNum(_))
+        ));
+        assert_eq!(stream.next(), None);
+    }
+
+    fn round_trip(pgm: &PGM<'_>, options: &WriteOptions) {
+        let mut written = vec![];
+        write_pgm(&mut written, pgm, options).unwrap();
+
+        assert_eq!(parse_pgm(&written), Ok((pgm.clone(), "".as_bytes())));
+    }
+
+    fn gradient(width: usize, height: usize, max_grey_val: u16) -> PGM<'static> {
+        let contents = (0..width * height)
+            .map(|i| (i * usize::from(max_grey_val) / (width * height).max(1)) as u16)
+            .flat_map(|sample| sample.to_be_bytes()[2 - sample_width(max_grey_val)..].to_vec())
+            .collect::<Vec<_>>();
+
+        PGM {
+            width,
+            height,
+            max_grey_val,
+            contents: contents.into(),
+        }
+    }
+
+    #[test]
+    fn write_pgm_round_trip_lolcat() {
+        let input = std::fs::read("assets/lolcat.pgm").unwrap();
+        let (lolcat, _) = parse_pgm(&input).unwrap();
+
+        round_trip(&lolcat, &WriteOptions::default());
+        round_trip(
+            &lolcat,
+            &WriteOptions {
+                encoding: Encoding::Plain,
+                ..WriteOptions::default()
+            },
+        );
+
+        let mut written = vec![];
+        write_pgm(&mut written, &lolcat, &WriteOptions::default()).unwrap();
+        assert_eq!(written, input);
+    }
+
+    #[test]
+    fn write_pgm_round_trip_generated() {
+        for &(width, height, max_grey_val) in &[(1, 1, 1), (7, 3, 255), (5, 4, 1023), (0, 0, 255)] {
+            let pgm = gradient(width, height, max_grey_val);
+
+            for &encoding in &[Encoding::Plain, Encoding::Raw] {
+                for &line_width in &[None, Some(1), Some(8), Some(70)] {
+                    round_trip(
+                        &pgm,
+                        &WriteOptions {
+                            encoding,
+                            line_width,
+                            comments: vec!["generated\nby tests".to_string()],
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_pgm_plain_layout() {
+        let mut written = vec![];
+        let options = WriteOptions {
+            encoding: Encoding::Plain,
+            line_width: Some(8),
+            comments: vec!["hi".to_string()],
+        };
+        write_pgm(&mut written, &gradient(5, 2, 1000), &options).unwrap();
+
+        assert_eq!(
+            written.as_bstr(),
+            "P2\n# hi\n5 2\n1000\n0 100\n200 300\n400\n500 600\n700 800\n900\n"
+                .as_bytes()
+                .as_bstr()
+        );
+    }
+
+    #[test]
+    fn parse_pbm_plain_and_raw_agree() {
+        let (plain, _) = parse_pbm(b"P1\n# bits\n10 2\n0100000001\n11 1 1 1 1 1 1 1 1\n").unwrap();
+        let (pbm, _) = parse_pbm(b"P4\n10 2\n\x40\x40\xff\xc0").unwrap();
+
+        assert_eq!(plain, pbm);
+        assert_eq!(pbm.pixel(1, 0), Some(true));
+        assert_eq!(pbm.pixel(2, 0), Some(false));
+        assert_eq!(pbm.pixel(9, 1), Some(true));
+        assert_eq!(pbm.pixel(10, 1), None);
+    }
+
+    #[test]
+    fn parse_pbm_invalid_bit() {
+        assert_eq!(
+            parse_pbm(b"P1\n2 1\n0 2"),
+            Err((ParseErr::InvalidBit(b'2'), "2".as_bytes()))
+        );
+    }
+
+    #[test]
+    fn parse_ppm_plain_and_raw_agree() {
+        let (plain, _) = parse_ppm(b"P3\n2 1\n1000\n1 2 3\n1000 0 500\n").unwrap();
+        let (ppm, _) =
+            parse_ppm(b"P6\n2 1\n1000\n\x00\x01\x00\x02\x00\x03\x03\xe8\x00\x00\x01\xf4").unwrap();
+
+        assert_eq!(plain, ppm);
+        assert_eq!(
+            ppm.pixels().collect::<Vec<_>>(),
+            vec![[1, 2, 3], [1000, 0, 500]]
+        );
+        assert_eq!(ppm.pixel(0, 1), None);
+    }
+
+    #[test]
+    fn parse_netpbm_dispatches_on_magic() {
+        let kinds: Vec<_> = [
+            &b"P1\n1 1\n1"[..],
+            b"P5\n1 1\n255\n\x07",
+            b"P6\n1 1\n255\n\x01\x02\x03",
+        ]
+        .iter()
+        .map(|input| parse_netpbm(input).ok().map(|(image, _)| image.kind()))
+        .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                Some(ImageKind::Pbm),
+                Some(ImageKind::Pgm),
+                Some(ImageKind::Ppm)
+            ]
+        );
+        assert_eq!(
+            parse_pgm(b"P6\n1 1\n255\n\x01\x02\x03").map(|(pgm, _)| pgm),
+            Err((
+                ParseErr::Expected(vec!["P2".to_string(), "P5".to_string()]),
+                "P6\n1 1\n255\n\x01\x02\x03".as_bytes()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_pam_rgb_alpha() {
+        let input = indoc!(
+            "P7
+            # keywords may come in any order
+            HEIGHT 1
+            WIDTH 2
+            DEPTH 4
+            MAXVAL 255
+            TUPLTYPE RGB_ALPHA
+            ENDHDR
+            \x01\x02\x03\x04\x05\x06\x07\x08"
+        );
+
+        let (pam, rest) = parse_pam(input.as_bytes()).unwrap();
+
+        assert_eq!(rest, b"");
+        assert_eq!(pam.tuple_type, TupleType::RgbAlpha);
+        assert_eq!(
+            pam.tuples().collect::<Vec<_>>(),
+            vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8]]
+        );
+        assert_eq!(pam.tuple(1, 0), Some(vec![5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn parse_pam_header_errors() {
+        let missing = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nTUPLTYPE GRAYSCALE\nENDHDR\n\x00";
+        let unknown = b"P7\nWIDTH 1\nTUPLTYPE CMYK\n";
+        let mismatch =
+            b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 1\nTUPLTYPE GRAYSCALE\nENDHDR\n\x00";
+
+        assert!(matches!(
+            parse_pam(missing),
+            Err((ParseErr::MissingPamField("MAXVAL"), _))
+        ));
+        assert!(matches!(
+            parse_pam(unknown),
+            Err((ParseErr::UnknownTupleType(ref name), _)) if name == "CMYK"
+        ));
+        assert!(matches!(
+            parse_pam(mismatch),
+            Err((ParseErr::TupleDepthMismatch("GRAYSCALE", 3), _))
+        ));
+
+        let negative =
+            b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH -1\nMAXVAL 1\nTUPLTYPE GRAYSCALE\nENDHDR\n\x00";
+        assert!(matches!(
+            parse_pam(negative),
+            Err((ParseErr::InvalidDepth(-1), _))
+        ));
+    }
+
+    #[test]
+    fn write_pam_round_trip() {
+        for &tuple_type in &TupleType::ALL {
+            for &max_val in &[255, 65535] {
+                let contents: Vec<u8> = (0..3 * 2 * tuple_type.depth() * sample_width(max_val))
+                    .map(|i| i as u8)
+                    .collect();
+                let pam = PAM {
+                    width: 3,
+                    height: 2,
+                    max_val,
+                    tuple_type,
+                    contents: contents.into(),
+                };
+
+                let mut written = vec![];
+                write_pam(&mut written, &pam).unwrap();
+
+                assert_eq!(parse_netpbm(&written), Ok((Image::Pam(pam), "".as_bytes())));
+            }
+        }
+    }
+
+    #[test]
+    fn parse_locates_errors() {
+        let header = indoc!(
+            "P2
+            # a comment
+            3 x2
+            255"
+        );
+
+        assert!(matches!(
+            parse(parse_pgm, header.as_bytes()),
+            Err(ParseError {
+                err,
+                position: Position {
+                    offset: 17,
+                    line: 3,
+                    column: 3,
+                },
+            }) if matches!(err.root(), ParseErr::InvalidNum(_))
+        ));
+        assert_eq!(
+            parse(parse_pbm, b"P1\n2 2\n0 1\n1 x"),
+            Err(ParseError {
+                err: ParseErr::InvalidBit(b'x'),
+                position: Position {
+                    offset: 13,
+                    line: 4,
+                    column: 3,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn report_underlines_token() {
+        let input = b"P2\n# a comment\n3 x2\n255\n";
+        let err = parse(parse_pgm, input).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "while parsing PGM header > height: invalid number at line 3, column 3 (byte 17)"
+        );
+        assert_eq!(
+            err.report(input).to_string(),
+            indoc!(
+                "while parsing PGM header > height: invalid number at line 3, column 3 (byte 17): invalid digit found in string
+                  |
+                3 | 3 x2
+                  |   ^^"
+            )
+        );
+
+        let tabbed = b"P2\n3\tx 255\n";
+        let report = parse(parse_pgm, tabbed)
+            .unwrap_err()
+            .report(tabbed)
+            .to_string();
+        assert!(report.ends_with("2 | 3\tx 255\n  |  \t^"));
+    }
+
+    #[test]
+    fn report_skips_binary_lines() {
+        let input = b"\x89PNG\r\n\x1a\n";
+        let err = parse(parse_pgm, input).unwrap_err();
+
+        assert_eq!(
+            err.report(input).to_string(),
+            "expected P2 or P5 at line 1, column 1 (byte 0)"
+        );
+    }
+
+    #[test]
+    fn choice_merges_expectations() {
+        let err = parse(parse_netpbm, b"P9\n1 1\n").unwrap_err();
+
+        assert_eq!(
+            err.err,
+            ParseErr::Expected(
+                ["P1", "P2", "P3", "P4", "P5", "P6", "P7"]
+                    .iter()
+                    .map(|magic| magic.to_string())
+                    .collect()
+            )
+        );
+        assert_eq!(
+            err.to_string(),
+            "expected P1, P2, P3, P4, P5, P6 or P7 at line 1, column 1 (byte 0)"
+        );
+    }
+
+    #[test]
+    fn match_header_version_needs_whitespace() {
+        assert_eq!(
+            parse(parse_pgm, b"P5x 1\n255\n\x00").map_err(|er| er.to_string()),
+            Err("expected whitespace at line 1, column 3 (byte 2)".to_string())
+        );
+    }
+
+    #[test]
+    fn raw_contents_borrow_input() {
+        let input = std::fs::read("assets/lolcat.pgm").unwrap();
+        let (lolcat, _) = parse_pgm(&input).unwrap();
+
+        assert!(matches!(*lolcat.contents, Cow::Borrowed(_)));
+        assert_eq!(lolcat.contents.as_ptr(), input[15..].as_ptr());
+
+        let owned: PGM<'static> = lolcat.clone().into_owned();
+        drop(input);
+        assert!(matches!(*owned.contents, Cow::Owned(_)));
+        assert_eq!(owned.samples().count(), 430 * 430);
+
+        let (plain, _) = parse_pgm(b"P2\n1 1\n255\n7\n").unwrap();
+        assert!(matches!(*plain.contents, Cow::Owned(_)));
+    }
+
+    #[test]
+    fn parse_truncated_raster() {
+        let raw = b"P5 2 2 65535\n\x00\x01\x00\x02\x00";
+        assert_eq!(
+            parse(parse_pgm, raw).unwrap_err(),
+            ParseError {
+                err: ParseErr::UnexpectedEof {
+                    needed: 8,
+                    available: 5,
+                },
+                position: Position {
+                    offset: 13,
+                    line: 2,
+                    column: 1,
+                },
+            }
+        );
+
+        let plain = b"P2 2 2 255\n1 2\n3\n";
+        assert_eq!(
+            parse(parse_pgm, plain).unwrap_err().err,
+            ParseErr::UnexpectedEof {
+                needed: 4,
+                available: 3,
+            }
+        );
+
+        let bits = b"P1 3 2\n010 1";
+        assert_eq!(
+            parse(parse_pbm, bits).unwrap_err().err,
+            ParseErr::UnexpectedEof {
+                needed: 6,
+                available: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_pgm_with_pads_truncated_raster() {
+        let options = ParseOptions {
+            pad_truncated: true,
+            ..ParseOptions::default()
+        };
+
+        let raw = b"P5 2 2 65535\n\x00\x01\x00\x02\x00";
+        let ((pgm, warnings), rest) = parse_pgm_with(raw, &options).unwrap();
+        assert_eq!(pgm.samples().collect::<Vec<_>>(), vec![1, 2, 0, 0]);
+        assert_eq!(rest, b"");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "unexpected end of input: needed 8, only 5 available at line 2, column 1 (byte 13)"
+        );
+
+        let plain = b"P2 2 2 255\n1 2\n3\n";
+        let ((pgm, warnings), _) = parse_pgm_with(plain, &options).unwrap();
+        assert_eq!(pgm.samples().collect::<Vec<_>>(), vec![1, 2, 3, 0]);
+        assert_eq!(warnings.len(), 1);
+
+        let intact = b"P2 1 1 255\n7\n";
+        let ((pgm, warnings), _) = parse_pgm_with(intact, &options).unwrap();
+        assert_eq!(pgm.samples().collect::<Vec<_>>(), vec![7]);
+        assert!(warnings.is_empty());
+
+        assert!(parse_pgm_with(raw, &ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn parse_pgm_rejects_invalid_header() {
+        let err = |input: &[u8]| parse(parse_pgm, input).unwrap_err();
+
+        assert_eq!(
+            err(b"P5 -3 2 255\n"),
+            ParseError {
+                err: ParseErr::Context(
+                    "PGM header",
+                    Box::new(ParseErr::InvalidDimensions {
+                        width: -3,
+                        height: 2,
+                    })
+                ),
+                position: Position {
+                    offset: 3,
+                    line: 1,
+                    column: 4,
+                },
+            }
+        );
+        assert_eq!(err(b"P5 3 2 0\n").err.root(), &ParseErr::InvalidMaxval(0));
+        assert_eq!(
+            err(b"P2 3 2 65536\n").err.root(),
+            &ParseErr::InvalidMaxval(65536)
+        );
+
+        let pam = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 0\nTUPLTYPE GRAYSCALE\nENDHDR\n\0";
+        assert_eq!(
+            parse(parse_pam, pam).unwrap_err().err,
+            ParseErr::InvalidMaxval(0)
+        );
+    }
+
+    #[test]
+    fn parse_pgm_rejects_samples_above_maxval() {
+        let raw = b"P5 2 2 100\n\x00\x64\x65\x00";
+        assert_eq!(
+            parse(parse_pgm, raw).unwrap_err(),
+            ParseError {
+                err: ParseErr::SampleExceedsMaxval {
+                    x: 0,
+                    y: 1,
+                    value: 101,
+                },
+                position: Position {
+                    offset: 13,
+                    line: 2,
+                    column: 3,
+                },
+            }
+        );
+
+        // Too big for the sample width, yet still reported as it was written.
+        let plain = b"P2 2 2 255\n0 1\n2 300\n";
+        let err = parse(parse_pgm, plain).unwrap_err();
+        assert_eq!(
+            err.err,
+            ParseErr::SampleExceedsMaxval {
+                x: 1,
+                y: 1,
+                value: 300,
+            }
+        );
+        assert_eq!(err.position.column, 3);
+
+        let options = ParseOptions {
+            strictness: Strictness::Lenient,
+            ..ParseOptions::default()
+        };
+        let ((pgm, warnings), _) = parse_pgm_with(plain, &options).unwrap();
+        assert_eq!(pgm.samples().collect::<Vec<_>>(), vec![0, 1, 2, 255]);
+        assert_eq!(warnings, vec![err]);
+
+        let decoded: Result<Vec<_>, _> = PgmDecoder::new(&plain[..]).unwrap().collect();
+        assert!(matches!(
+            decoded,
+            Err(DecodeErr::Parse(ParseError {
+                err: ParseErr::SampleExceedsMaxval {
+                    x: 1,
+                    y: 1,
+                    value: 300
+                },
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn parse_netpbm_limits() {
+        let limits = ParseLimits {
+            max_pixels: 4,
+            max_alloc_bytes: 8,
+            ..ParseLimits::default()
+        };
+        let exceeded = |input: &[u8]| match parse_netpbm_with(input, &limits) {
+            Ok(_) => None,
+            Err((ParseErr::LimitExceeded { limit, .. }, _)) => Some(limit),
+            Err((err, _)) => panic!("unexpected {:?}", err),
+        };
+
+        assert_eq!(exceeded(b"P1 2 2 0 1 1 0"), None);
+        assert_eq!(exceeded(b"P1 5 1 0 1 1 0 1"), Some("pixel count"));
+        // Plain samples are held two bytes each, whatever the maxval.
+        assert_eq!(exceeded(b"P2 2 2 255 1 2 3 4"), None);
+        assert_eq!(exceeded(b"P5 5 1 65535\n"), Some("pixel count"));
+        assert_eq!(exceeded(b"P6 2 1 255\n\0\0\0\0\0\0"), None);
+        assert_eq!(exceeded(b"P3 2 2 255\n"), Some("raster size"));
+        assert_eq!(
+            exceeded(b"P7\nWIDTH 2\nHEIGHT 2\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB\nENDHDR\n"),
+            Some("raster size")
+        );
+    }
+
+    #[test]
+    fn parse_limits() {
+        let huge = b"P5 2000000000 2000000000 255\n";
+        assert_eq!(
+            parse(parse_pgm, huge).unwrap_err(),
+            ParseError {
+                err: ParseErr::LimitExceeded {
+                    limit: "width",
+                    max: 1 << 20,
+                },
+                position: Position {
+                    offset: 29,
+                    line: 2,
+                    column: 1,
+                },
+            }
+        );
+
+        let options = ParseOptions {
+            limits: ParseLimits {
+                max_pixels: 100,
+                max_alloc_bytes: 150,
+                ..ParseLimits::default()
+            },
+            ..ParseOptions::default()
+        };
+        let exceeded = |input: &[u8]| parse_pgm_with(input, &options).unwrap_err().0;
+
+        assert!(parse_pgm_with(&[&b"P5 10 10 255\n"[..], &[0; 100]].concat(), &options).is_ok());
+        assert_eq!(
+            exceeded(b"P5 11 10 255\n"),
+            ParseErr::LimitExceeded {
+                limit: "pixel count",
+                max: 100,
+            }
+        );
+        assert_eq!(
+            exceeded(b"P5 10 10 65535\n"),
+            ParseErr::LimitExceeded {
+                limit: "raster size",
+                max: 150,
+            }
+        );
+        assert_eq!(
+            ParseLimits::default().check(usize::MAX, 2, 1),
+            Err(ParseErr::LimitExceeded {
+                limit: "width",
+                max: 1 << 20,
+            })
+        );
+
+        let unlimited = ParseLimits {
+            max_width: usize::MAX,
+            max_height: usize::MAX,
+            max_pixels: usize::MAX,
+            max_alloc_bytes: usize::MAX,
+            max_header_bytes: usize::MAX,
+        };
+        assert!(matches!(
+            unlimited.check(usize::MAX, 2, 1),
+            Err(ParseErr::LimitExceeded {
+                limit: "pixel count",
+                ..
+            })
+        ));
+        assert!(matches!(
+            unlimited.check(usize::MAX / 2, 2, 2),
+            Err(ParseErr::LimitExceeded {
+                limit: "raster size",
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            PgmDecoder::new(&huge[..]),
+            Err(DecodeErr::Parse(ParseError {
+                err: ParseErr::LimitExceeded { limit: "width", .. },
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn contents_debug_small_images() {
+        assert_eq!(format!("{:?}", Contents::from(&b"ab"[..])), "\"ab\"");
+        assert_eq!(
+            format!("{:?}", Contents::from(vec![b'x'; 30])),
+            format!("\"{}\"...<contents>", "x".repeat(20))
+        );
+    }
+
+    #[test]
+    fn probe_pgm_header() {
+        let input = std::fs::read("assets/lolcat.pgm").unwrap();
+        let lolcat = PgmHeader {
+            encoding: Encoding::Raw,
+            width: 430,
+            height: 430,
+            max_grey_val: 255,
+            raster_offset: 15,
+        };
+        assert_eq!(probe_pgm(&input), Ok(lolcat));
+        assert_eq!(probe_pgm(&input[..15]), Ok(lolcat));
+        assert_eq!(
+            probe_pgm(b"P5 430").unwrap_err().err.root(),
+            &ParseErr::NoValidFieldLeft
+        );
+
+        let mut file = std::fs::File::open("assets/lolcat.pgm").unwrap();
+        assert_eq!(read_pgm_header(&mut file).unwrap(), lolcat);
+        let mut raster = vec![];
+        file.read_to_end(&mut raster).unwrap();
+        assert_eq!(raster, &input[15..]);
+
+        // Offsets count from where the reader starts.
+        let stream = [&input[..], &input[..]].concat();
+        let mut cursor = io::Cursor::new(stream);
+        cursor.set_position(input.len() as u64);
+        assert_eq!(read_pgm_header(&mut cursor).unwrap(), lolcat);
+        assert_eq!(cursor.position(), input.len() as u64 + 15);
+
+        // A comment after the maxval belongs to the header, whichever way it is read.
+        let commented = b"P5\n1 1\n255# a comment\n\x07";
+        let mut cursor = io::Cursor::new(&commented[..]);
+        let header = read_pgm_header(&mut cursor).unwrap();
+        assert_eq!(Ok(header), probe_pgm(commented));
+        assert_eq!(header.raster_offset, 22);
+        assert_eq!(cursor.position(), 22);
+
+        // Reading only the header, there is nothing to hold against the limits.
+        let huge = b"P5 2000000 1 255\n";
+        let header = read_pgm_header_buffered(&mut &huge[..]).unwrap();
+        assert_eq!((header.width, header.raster_offset), (2_000_000, 17));
+    }
+
+    #[test]
+    fn decode_lolcat_from_file() {
+        let input = std::fs::read("assets/lolcat.pgm").unwrap();
+        let (lolcat, _) = parse_pgm(&input).unwrap();
+
+        let file = std::fs::File::open("assets/lolcat.pgm").unwrap();
+        let decoder = PgmDecoder::new(io::BufReader::new(file)).unwrap();
+        assert_eq!(decoder.header().width, 430);
+        assert_eq!(decoder.header().height, 430);
+
+        let rows: Vec<_> = decoder.collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows.len(), 430);
+        assert_eq!(rows.concat(), lolcat.contents.to_vec());
+    }
+
+    #[test]
+    fn decode_plain_one_byte_at_a_time() {
+        let input = b"P2\n# wide samples\n3 2\n# max\n300\n0 1 300\n 299\t2\n3";
+        let (pgm, _) = parse_pgm(input).unwrap();
+
+        // A capacity of 1 hands the decoder a single byte per read.
+        let decoder = PgmDecoder::new(io::BufReader::with_capacity(1, &input[..])).unwrap();
+        assert_eq!(
+            decoder.header(),
+            PgmHeader {
+                encoding: Encoding::Plain,
+                width: 3,
+                height: 2,
+                max_grey_val: 300,
+                raster_offset: 32,
+            }
+        );
+
+        let rows: Vec<_> = decoder.collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows, vec![&pgm.contents[..6], &pgm.contents[6..]]);
+    }
+
+    #[test]
+    fn decode_commented_header() {
+        let commented = indoc!(
+            "P2
+            # CREATOR: GIMP PNM Filter Version 1.1
+            2 # width
+            1
+            # maxval follows
+            255# comment ending the header
+            7 9"
+        );
+        let decoder = PgmDecoder::new(commented.as_bytes()).unwrap();
+        assert_eq!(decoder.header(), probe_pgm(commented.as_bytes()).unwrap());
+        assert_eq!(
+            decoder.collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![vec![7, 9]]
+        );
+
+        let raw = b"P5\n1 1\n255# a comment\n\x07";
+        let mut decoder = PgmDecoder::new(&raw[..]).unwrap();
+        assert_eq!(decoder.header().raster_offset, 22);
+        assert_eq!(decoder.next().unwrap().unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn decode_commented_plain_raster() {
+        let input = b"P2 2 2 255\n1 # c\n2\n#3\n3#4\n 4";
+        let (pgm, _) = parse_pgm(input).unwrap();
+
+        let rows = PgmDecoder::new(&input[..])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>();
+        assert_eq!(rows.unwrap().concat(), pgm.contents.to_vec());
+        assert_eq!(pgm.contents.to_vec(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn decode_long_samples() {
+        let endless = [&b"P2 1 1 255\n"[..], &[b'1'; 100_000]].concat();
+        let mut decoder = PgmDecoder::new(&endless[..]).unwrap();
+
+        match decoder.next() {
+            Some(Err(DecodeErr::Parse(ParseError {
+                err: ParseErr::InvalidNum(_),
+                position,
+            }))) => assert_eq!(position.offset, 11),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let padded = PgmDecoder::new(&b"P2 1 1 255\n0000001"[..]).unwrap().next();
+        assert!(matches!(
+            padded,
+            Some(Err(DecodeErr::Parse(ParseError {
+                err: ParseErr::InvalidNum(_),
+                ..
+            })))
+        ));
+        let signed = PgmDecoder::new(&b"P2 1 1 255\n+00255"[..]).unwrap().next();
+        assert_eq!(signed.unwrap().unwrap(), vec![255]);
+    }
+
+    #[test]
+    fn decode_long_headers() {
+        // Padding that used to get the header parsed over again at every byte.
+        let padded = [&b"P5"[..], &[b' '; 100_000], b"1 1 255\n\x07"].concat();
+        let unlimited = ParseLimits {
+            max_header_bytes: usize::MAX,
+            ..ParseLimits::default()
+        };
+        let decoder = PgmDecoder::with_limits(&padded[..], unlimited).unwrap();
+        assert_eq!(decoder.header().raster_offset, padded.len() - 1);
+
+        assert!(matches!(
+            PgmDecoder::new(&padded[..]),
+            Err(DecodeErr::Parse(ParseError {
+                err: ParseErr::LimitExceeded {
+                    limit: "header size",
+                    max: 65536,
+                },
+                position: Position { offset: 65536, .. },
+            }))
+        ));
+    }
+
+    #[test]
+    fn decode_errors() {
+        let header_err = PgmDecoder::new(&b"P7\nWIDTH 1"[..]).err().unwrap();
+        assert!(matches!(
+            header_err,
+            DecodeErr::Parse(ParseError {
+                err: ParseErr::Expected(_),
+                position: Position { offset: 0, .. }
+            })
+        ));
+
+        let mut plain = PgmDecoder::new(&b"P2 2 2 255\n1 2\n3 x"[..]).unwrap();
+        assert_eq!(plain.next().unwrap().unwrap(), vec![1, 2]);
+        match plain.next() {
+            Some(Err(DecodeErr::Parse(err))) => assert_eq!(
+                err.position,
+                Position {
+                    offset: 17,
+                    line: 3,
+                    column: 3,
+                }
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(plain.next().is_none());
+
+        let mut raw = PgmDecoder::new(&b"P5 2 2 255\n\x01\x02\x03"[..]).unwrap();
+        assert_eq!(raw.next().unwrap().unwrap(), vec![1, 2]);
+        match raw.next() {
+            Some(Err(DecodeErr::Parse(err))) => assert_eq!(
+                err,
+                ParseError {
+                    err: ParseErr::UnexpectedEof {
+                        needed: 4,
+                        available: 3,
+                    },
+                    position: Position {
+                        offset: 14,
+                        line: 2,
+                        column: 4,
+                    },
+                }
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(raw.next().is_none());
+    }
+}
+
+/**************/
+/* Benchmarks */
+/**************/
+
+/// Run with `cargo +nightly bench --features bench`.
+#[cfg(all(test, feature = "bench"))]
+mod benches {
+    extern crate test;
+
+    use super::*;
+    use test::{black_box, Bencher};
+
+    fn lolcat() -> Vec<u8> {
+        std::fs::read("assets/lolcat.pgm").unwrap()
+    }
+
+    #[bench]
+    fn parse_lolcat_borrowed(b: &mut Bencher) {
+        let input = lolcat();
+
+        b.iter(|| parse_pgm(black_box(&input)).unwrap().0.contents.len());
+    }
+
+    #[bench]
+    fn parse_lolcat_owned(b: &mut Bencher) {
+        let input = lolcat();
+
+        b.iter(|| parse_pgm(black_box(&input)).unwrap().0.into_owned());
+    }
+
+    /// How the raster used to be read, one byte at a time through `io::Read`, for comparison.
+    #[bench]
+    fn copy_lolcat_bytewise(b: &mut Bencher) {
+        let input = lolcat();
+        let raster = &input[15..];
+
+        b.iter(|| {
+            <ParseInput as Read>::bytes(black_box(raster))
+                .collect::<Result<Vec<u8>, _>>()
+                .unwrap()
+        });
+    }
+}